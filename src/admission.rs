@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::pin::pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+use crate::config::{LimitsConfig, SharedConfig};
+
+/// The cap that turned a request away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Transcodes,
+    SourceHost,
+    Client,
+}
+
+impl Limit {
+    fn message(self) -> &'static str {
+        match self {
+            Limit::Transcodes => "Too many concurrent transcodes; try again later",
+            Limit::SourceHost => "Too many concurrent connections to this camera; try again later",
+            Limit::Client => "Too many concurrent sessions from this client; try again later",
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("{}", .limit.message())]
+pub struct Rejected {
+    pub limit: Limit,
+    /// How long the client should wait before retrying
+    pub retry_after: Duration,
+}
+
+/// What a new ffmpeg would count against.
+#[derive(Debug, Clone, Default)]
+pub struct Claim {
+    source_host: Option<String>,
+    client: Option<IpAddr>,
+}
+
+impl Claim {
+    /// A claim for an ffmpeg reading `source_url`, started on behalf of
+    /// `client` (`None` for streams the server starts itself).
+    pub fn new(source_url: &str, client: Option<IpAddr>) -> Self {
+        let source_host = url::Url::parse(source_url)
+            .ok()
+            .and_then(|url| url.host_str().map(|host| host.to_ascii_lowercase()));
+        Self { source_host, client }
+    }
+}
+
+#[derive(Default)]
+struct Usage {
+    transcodes: usize,
+    hosts: HashMap<String, usize>,
+    clients: HashMap<IpAddr, usize>,
+    queued: usize,
+}
+
+impl Usage {
+    /// The first limit `claim` would exceed, if any. A limit of 0 is unlimited.
+    fn exceeded(&self, limits: &LimitsConfig, claim: &Claim) -> Option<Limit> {
+        let over = |max: usize, used: usize| max > 0 && used >= max;
+        if over(limits.max_transcodes, self.transcodes) {
+            return Some(Limit::Transcodes);
+        }
+        if let Some(host) = &claim.source_host {
+            if over(limits.max_per_source_host, self.hosts.get(host).copied().unwrap_or(0)) {
+                return Some(Limit::SourceHost);
+            }
+        }
+        if let Some(client) = &claim.client {
+            if over(limits.max_per_client, self.clients.get(client).copied().unwrap_or(0)) {
+                return Some(Limit::Client);
+            }
+        }
+        None
+    }
+
+    fn add(&mut self, claim: &Claim) {
+        self.transcodes += 1;
+        if let Some(host) = &claim.source_host {
+            *self.hosts.entry(host.clone()).or_default() += 1;
+        }
+        if let Some(client) = claim.client {
+            *self.clients.entry(client).or_default() += 1;
+        }
+    }
+
+    fn remove(&mut self, claim: &Claim) {
+        self.transcodes = self.transcodes.saturating_sub(1);
+        if let Some(host) = &claim.source_host {
+            decrement(&mut self.hosts, host);
+        }
+        if let Some(client) = &claim.client {
+            decrement(&mut self.clients, client);
+        }
+    }
+}
+
+fn decrement<K: std::hash::Hash + Eq>(counts: &mut HashMap<K, usize>, key: &K) {
+    if let Some(count) = counts.get_mut(key) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(key);
+        }
+    }
+}
+
+/// Admission control for ffmpeg work: caps on total transcodes, connections
+/// per camera host and sessions per client address, read from `[limits]` on
+/// every request so a reload applies immediately.
+///
+/// Requests over a limit wait in a bounded queue for up to
+/// `limits.queue_timeout_secs`; once the queue is full they are rejected
+/// straight away.
+#[derive(Clone)]
+pub struct Admission {
+    config: SharedConfig,
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    usage: Mutex<Usage>,
+    /// Signalled whenever a permit is released
+    released: Notify,
+}
+
+/// Holds a slot until dropped, i.e. until the ffmpeg it was taken for is gone.
+pub struct Permit {
+    inner: Arc<Inner>,
+    claim: Claim,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.inner.lock().remove(&self.claim);
+        self.inner.released.notify_waiters();
+    }
+}
+
+/// Counts a request in the queue for as long as it waits.
+struct QueueSlot<'a> {
+    inner: &'a Inner,
+}
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.inner.lock().queued -= 1;
+    }
+}
+
+impl Inner {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Usage> {
+        self.usage.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Admission {
+    pub fn new(config: SharedConfig) -> Self {
+        Self {
+            config,
+            inner: Arc::new(Inner {
+                usage: Mutex::new(Usage::default()),
+                released: Notify::new(),
+            }),
+        }
+    }
+
+    /// Take a slot if one is free, without waiting.
+    pub fn try_acquire(&self, claim: &Claim) -> Result<Permit, Rejected> {
+        let limits = self.config.current().limits.clone();
+        self.try_acquire_with(&limits, claim)
+    }
+
+    fn try_acquire_with(&self, limits: &LimitsConfig, claim: &Claim) -> Result<Permit, Rejected> {
+        let mut usage = self.inner.lock();
+        if let Some(limit) = usage.exceeded(limits, claim) {
+            return Err(Rejected {
+                limit,
+                retry_after: Duration::from_secs(limits.retry_after_secs),
+            });
+        }
+        usage.add(claim);
+        Ok(Permit {
+            inner: self.inner.clone(),
+            claim: claim.clone(),
+        })
+    }
+
+    /// Take a slot, queueing for one if the limits are reached and the queue
+    /// has room.
+    pub async fn acquire(&self, claim: &Claim) -> Result<Permit, Rejected> {
+        let limits = self.config.current().limits.clone();
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(limits.queue_timeout_secs);
+        let mut slot = None;
+        loop {
+            // Register for wakeups before checking, so a release in between isn't missed
+            let mut released = pin!(self.inner.released.notified());
+            released.as_mut().enable();
+
+            let rejected = match self.try_acquire_with(&limits, claim) {
+                Ok(permit) => return Ok(permit),
+                Err(rejected) => rejected,
+            };
+            if slot.is_none() {
+                let mut usage = self.inner.lock();
+                if usage.queued >= limits.queue_size {
+                    warn!("Admission refused: {}", rejected);
+                    return Err(rejected);
+                }
+                usage.queued += 1;
+                slot = Some(QueueSlot { inner: &self.inner });
+                info!("Request queued for an ffmpeg slot ({} waiting)", usage.queued);
+            }
+            if tokio::time::timeout_at(deadline, released).await.is_err() {
+                warn!("Admission refused after waiting: {}", rejected);
+                return Err(rejected);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn admission(limits: LimitsConfig) -> Admission {
+        let config = Config {
+            limits,
+            ..Config::default()
+        };
+        Admission::new(SharedConfig::new(Arc::new(config)))
+    }
+
+    #[test]
+    fn enforces_each_limit_and_frees_slots_on_drop() {
+        let admission = admission(LimitsConfig {
+            max_transcodes: 3,
+            max_per_source_host: 2,
+            max_per_client: 1,
+            ..LimitsConfig::default()
+        });
+        let alice = Some(IpAddr::from([10, 0, 0, 1]));
+        let bob = Some(IpAddr::from([10, 0, 0, 2]));
+        let camera = "rtsp://admin:pw@Camera-1:554/live";
+
+        let first = admission.try_acquire(&Claim::new(camera, alice)).unwrap();
+        let limit = |claim: Claim| admission.try_acquire(&claim).err().map(|e| e.limit);
+        assert_eq!(limit(Claim::new("rtsp://camera-2/live", alice)), Some(Limit::Client));
+
+        let _second = admission.try_acquire(&Claim::new(camera, bob)).unwrap();
+        assert_eq!(limit(Claim::new("rtsp://camera-1/sub", None)), Some(Limit::SourceHost));
+
+        let _third = admission.try_acquire(&Claim::new("rtsp://camera-2/live", None)).unwrap();
+        assert_eq!(limit(Claim::new("rtsp://camera-3/live", None)), Some(Limit::Transcodes));
+
+        drop(first);
+        assert!(admission.try_acquire(&Claim::new(camera, alice)).is_ok());
+    }
+
+    #[tokio::test]
+    async fn queued_requests_get_released_slots() {
+        let admission = admission(LimitsConfig {
+            max_transcodes: 1,
+            queue_size: 1,
+            ..LimitsConfig::default()
+        });
+        let claim = Claim::new("rtsp://camera/live", None);
+        let held = admission.try_acquire(&claim).unwrap();
+
+        let waiting = tokio::spawn({
+            let admission = admission.clone();
+            let claim = claim.clone();
+            async move { admission.acquire(&claim).await.map(|_| ()) }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // The queue is full, so a second waiter is turned away at once
+        assert!(admission.acquire(&claim).await.is_err());
+
+        drop(held);
+        assert!(waiting.await.unwrap().is_ok());
+    }
+}
@@ -0,0 +1,27 @@
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+
+/// Replace `path` with `contents` atomically: write `<path>.tmp`, fsync,
+/// rename. A crash mid-write never leaves a truncated file behind.
+pub async fn write(path: &Path, contents: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    let mut tmp_path = path.to_path_buf().into_os_string();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let mut tmp = tokio::fs::File::create(&tmp_path)
+        .await
+        .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+    tmp.write_all(contents).await?;
+    tmp.sync_all().await?;
+    drop(tmp);
+
+    tokio::fs::rename(&tmp_path, path)
+        .await
+        .with_context(|| format!("Failed to replace {}", path.display()))?;
+    Ok(())
+}
@@ -0,0 +1,430 @@
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::config::{ApiKeyConfig, AuthConfig, Config, SharedConfig};
+
+/// What a key may do. Each route belongs to exactly one scope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Watch streams: playlists, segments, MPEG-TS and HLS session creation
+    View,
+    /// Start and stop managed streams and manage HLS sessions
+    Control,
+    /// Hikvision NVR proxy routes
+    Nvr,
+    /// Reload, cleanup, credentials, log filter, metrics and diagnostics
+    Admin,
+}
+
+/// A preset bundle of scopes for a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Watch streams
+    Viewer,
+    /// Also start and stop streams, manage HLS sessions and use the NVR proxy
+    Operator,
+    /// Everything, including reload and cleanup
+    Admin,
+}
+
+impl Role {
+    fn scopes(self) -> &'static [Scope] {
+        match self {
+            Role::Viewer => &[Scope::View],
+            Role::Operator => &[Scope::View, Scope::Control, Scope::Nvr],
+            Role::Admin => &[Scope::View, Scope::Control, Scope::Nvr, Scope::Admin],
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuthError {
+    /// No key, or one that isn't configured
+    Unauthenticated,
+    /// A valid key without the route's scope
+    Forbidden { key: String },
+    /// A valid key limited to other streams
+    StreamForbidden { key: String },
+}
+
+/// Which streams a request may touch. The authorizers attach it to every
+/// request they let through, for handlers to check the stream in question.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Access {
+    /// Auth disabled, a key without stream limits, or a signed playback token
+    All,
+    /// A key limited to some streams and camera groups
+    Limited {
+        key: String,
+        streams: Vec<String>,
+        groups: Vec<String>,
+    },
+}
+
+impl Access {
+    fn for_key(key: &ApiKeyConfig) -> Self {
+        if key.streams.is_empty() && key.groups.is_empty() {
+            return Access::All;
+        }
+        Access::Limited {
+            key: key.name.clone(),
+            streams: key.streams.clone(),
+            groups: key.groups.clone(),
+        }
+    }
+
+    /// Whether the stream `id` is listed for this key or tagged with one of
+    /// its groups.
+    pub fn allows_stream(&self, config: &Config, id: &str) -> bool {
+        match self {
+            Access::All => true,
+            Access::Limited { streams, groups, .. } => {
+                streams.iter().any(|s| s == id)
+                    || config.streams.iter().any(|s| s.id == id && shares_group(&s.groups, groups))
+            }
+        }
+    }
+
+    /// Whether `url` is the source of a stream this key may access, or points
+    /// at an NVR in one of its groups. Credentials in the URLs don't matter.
+    pub fn allows_source(&self, config: &Config, url: &str) -> bool {
+        let Access::Limited { groups, .. } = self else {
+            return true;
+        };
+        let allowed_stream = config
+            .streams
+            .iter()
+            .filter(|s| self.allows_stream(config, &s.id))
+            .any(|s| config.stream_source(s).is_ok_and(|source| same_source(&source, url)));
+        allowed_stream
+            || url::Url::parse(url)
+                .ok()
+                .and_then(|url| url.host_str().map(|host| nvr_in_groups(config, host, groups)))
+                .unwrap_or(false)
+    }
+
+    /// Whether the Hikvision NVR at `host` may be used: it is in one of the
+    /// key's groups, or carries a stream the key may access on `channel`
+    /// (any channel when `None`).
+    fn allows_nvr(&self, config: &Config, host: &str, channel: Option<u32>) -> bool {
+        let Access::Limited { groups, .. } = self else {
+            return true;
+        };
+        nvr_in_groups(config, host, groups)
+            || config.streams.iter().any(|s| {
+                let on_host = s
+                    .nvr
+                    .as_ref()
+                    .and_then(|name| config.nvrs.get(name))
+                    .is_some_and(|nvr| nvr.host.eq_ignore_ascii_case(host));
+                on_host && channel.is_none_or(|c| c == s.channel) && self.allows_stream(config, &s.id)
+            })
+    }
+
+    pub fn check_stream(&self, config: &Config, id: &str) -> Result<(), AuthError> {
+        self.require(self.allows_stream(config, id), || format!("stream {}", id))
+    }
+
+    pub fn check_source(&self, config: &Config, url: &str) -> Result<(), AuthError> {
+        self.require(self.allows_source(config, url), || {
+            let host = url::Url::parse(url).ok().and_then(|u| u.host_str().map(str::to_string));
+            format!("source on {}", host.as_deref().unwrap_or("an unknown host"))
+        })
+    }
+
+    pub fn check_nvr(&self, config: &Config, host: &str, channel: Option<u32>) -> Result<(), AuthError> {
+        self.require(self.allows_nvr(config, host, channel), || match channel {
+            Some(channel) => format!("NVR {} channel {}", host, channel),
+            None => format!("NVR {}", host),
+        })
+    }
+
+    fn require(&self, allowed: bool, resource: impl FnOnce() -> String) -> Result<(), AuthError> {
+        match self {
+            Access::Limited { key, .. } if !allowed => {
+                warn!("Key \"{}\" may not access {}", key, resource());
+                Err(AuthError::StreamForbidden { key: key.clone() })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn shares_group(tags: &[String], groups: &[String]) -> bool {
+    tags.iter().any(|tag| groups.contains(tag))
+}
+
+fn nvr_in_groups(config: &Config, host: &str, groups: &[String]) -> bool {
+    config
+        .nvrs
+        .values()
+        .any(|nvr| nvr.host.eq_ignore_ascii_case(host) && shares_group(&nvr.groups, groups))
+}
+
+/// Whether two RTSP URLs name the same source, ignoring credentials.
+fn same_source(a: &str, b: &str) -> bool {
+    let (Ok(a), Ok(b)) = (url::Url::parse(a), url::Url::parse(b)) else {
+        return false;
+    };
+    let port = |url: &url::Url| {
+        url.port().unwrap_or(match url.scheme() {
+            "rtsps" => 322,
+            _ => 554,
+        })
+    };
+    a.scheme() == b.scheme()
+        && a.host_str().map(str::to_ascii_lowercase) == b.host_str().map(str::to_ascii_lowercase)
+        && port(&a) == port(&b)
+        && a.path() == b.path()
+        && a.query() == b.query()
+}
+
+/// Middleware state: the scope required by the routes it guards.
+#[derive(Clone)]
+pub struct Authorizer {
+    config: SharedConfig,
+    scope: Scope,
+}
+
+impl Authorizer {
+    pub fn new(config: SharedConfig, scope: Scope) -> Self {
+        Self { config, scope }
+    }
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        match self {
+            AuthError::Unauthenticated => {
+                let mut response = (StatusCode::UNAUTHORIZED, "Missing or invalid API key").into_response();
+                response
+                    .headers_mut()
+                    .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+                response
+            }
+            AuthError::Forbidden { .. } => {
+                (StatusCode::FORBIDDEN, "API key not allowed to access this endpoint").into_response()
+            }
+            AuthError::StreamForbidden { .. } => {
+                (StatusCode::FORBIDDEN, "API key not allowed to access this stream").into_response()
+            }
+        }
+    }
+}
+
+/// Reject requests without a key carrying the route's scope, and attach the
+/// key's [`Access`] to the rest.
+pub async fn authorize(State(authorizer): State<Authorizer>, mut request: Request, next: Next) -> Response {
+    match require_key(&authorizer.config.current().auth, &request, authorizer.scope) {
+        Ok(access) => {
+            request.extensions_mut().insert(access);
+            next.run(request).await
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Check the request's key when auth is enabled: 401 if the key is missing
+/// or unknown, 403 if it lacks `scope`.
+pub fn require_key(config: &AuthConfig, request: &Request, scope: Scope) -> Result<Access, AuthError> {
+    if !config.enabled {
+        return Ok(Access::All);
+    }
+
+    match check(config, request.headers(), scope) {
+        Ok(key) => Ok(Access::for_key(key)),
+        Err(e) => {
+            match &e {
+                AuthError::Forbidden { key } => {
+                    warn!("Key \"{}\" lacks the {:?} scope for {}", key, scope, request.uri().path());
+                }
+                _ => warn!("Rejected unauthenticated request for {}", request.uri().path()),
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Check that the request's key also has `scope`, for things a route allows
+/// only some of its callers, like revealing credentials.
+pub fn require_scope(config: &AuthConfig, headers: &HeaderMap, scope: Scope) -> Result<(), AuthError> {
+    if !config.enabled {
+        return Ok(());
+    }
+    check(config, headers, scope).map(|_| ()).inspect_err(|e| {
+        if let AuthError::Forbidden { key } = e {
+            warn!("Key \"{}\" lacks the {:?} scope", key, scope);
+        }
+    })
+}
+
+fn check<'a>(config: &'a AuthConfig, headers: &HeaderMap, scope: Scope) -> Result<&'a ApiKeyConfig, AuthError> {
+    let key = find_key(config, headers).ok_or(AuthError::Unauthenticated)?;
+    if key.scopes.contains(&scope) || key.role.is_some_and(|role| role.scopes().contains(&scope)) {
+        Ok(key)
+    } else {
+        Err(AuthError::Forbidden { key: key.name.clone() })
+    }
+}
+
+/// Name of the configured key the request presents, whatever its scopes.
+pub fn key_name<'a>(config: &'a AuthConfig, headers: &HeaderMap) -> Option<&'a str> {
+    find_key(config, headers).map(|key| key.name.as_str())
+}
+
+fn find_key<'a>(config: &'a AuthConfig, headers: &HeaderMap) -> Option<&'a ApiKeyConfig> {
+    let presented = presented_key(headers)?;
+    config
+        .keys
+        .iter()
+        .find(|key| constant_time_eq(key.key.as_bytes(), presented.as_bytes()))
+}
+
+/// The key from `X-API-Key` or `Authorization: Bearer <key>`.
+fn presented_key(headers: &HeaderMap) -> Option<&str> {
+    if let Some(key) = headers.get("x-api-key").and_then(|v| v.to_str().ok()) {
+        return Some(key.trim());
+    }
+    let authorization = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = authorization.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+}
+
+/// Compare without exiting early, so timing doesn't reveal how much of a
+/// guessed key was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> AuthConfig {
+        AuthConfig {
+            enabled: true,
+            keys: vec![ApiKeyConfig {
+                name: "wall-display".to_string(),
+                key: "0123456789abcdef".to_string(),
+                role: None,
+                scopes: vec![Scope::View],
+                streams: Vec::new(),
+                groups: Vec::new(),
+            }],
+        }
+    }
+
+    fn headers(name: &'static str, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn accepts_api_key_and_bearer_headers() {
+        let config = config();
+        for headers in [
+            headers("x-api-key", "0123456789abcdef"),
+            headers("authorization", "Bearer 0123456789abcdef"),
+            headers("authorization", "bearer 0123456789abcdef"),
+        ] {
+            assert_eq!(check(&config, &headers, Scope::View).map(|k| k.name.as_str()), Ok("wall-display"));
+        }
+    }
+
+    #[test]
+    fn rejects_missing_unknown_and_underscoped_keys() {
+        let config = config();
+        assert_eq!(check(&config, &HeaderMap::new(), Scope::View).err(), Some(AuthError::Unauthenticated));
+        assert_eq!(
+            check(&config, &headers("authorization", "Basic 0123456789abcdef"), Scope::View).err(),
+            Some(AuthError::Unauthenticated)
+        );
+        assert_eq!(
+            check(&config, &headers("x-api-key", "0123456789abcdeX"), Scope::View).err(),
+            Some(AuthError::Unauthenticated)
+        );
+        assert_eq!(
+            check(&config, &headers("x-api-key", "0123456789abcdef"), Scope::Control).err(),
+            Some(AuthError::Forbidden {
+                key: "wall-display".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn roles_grant_their_scopes() {
+        let mut config = config();
+        config.keys[0].role = Some(Role::Operator);
+        let key = headers("x-api-key", "0123456789abcdef");
+        assert!(check(&config, &key, Scope::Control).is_ok());
+        assert!(check(&config, &key, Scope::Nvr).is_ok());
+        assert!(check(&config, &key, Scope::Admin).is_err());
+    }
+
+    #[test]
+    fn limited_access_covers_listed_streams_groups_and_nvrs() {
+        let config: Config = toml::from_str(
+            r#"
+            [nvrs.main]
+            host = "10.0.0.50"
+
+            [nvrs.yard]
+            host = "10.0.0.60"
+            groups = ["outdoor"]
+
+            [[streams]]
+            id = "lobby"
+            url = "rtsp://10.0.0.10/live"
+
+            [[streams]]
+            id = "gate"
+            url = "rtsp://admin:pw@10.0.0.11:554/live"
+            groups = ["outdoor"]
+
+            [[streams]]
+            id = "office"
+            nvr = "main"
+            channel = 3
+            groups = ["outdoor"]
+            "#,
+        )
+        .unwrap();
+        let access = Access::Limited {
+            key: "security".to_string(),
+            streams: vec!["adhoc".to_string()],
+            groups: vec!["outdoor".to_string()],
+        };
+
+        assert!(access.allows_stream(&config, "adhoc"));
+        assert!(access.allows_stream(&config, "gate"));
+        assert!(!access.allows_stream(&config, "lobby"));
+
+        assert!(access.allows_source(&config, "rtsp://10.0.0.11/live"));
+        assert!(access.allows_source(&config, "rtsp://10.0.0.60/anything"));
+        assert!(!access.allows_source(&config, "rtsp://10.0.0.10/live"));
+        assert!(!access.allows_source(&config, "rtsp://10.0.0.11/other"));
+
+        assert!(access.allows_nvr(&config, "10.0.0.50", Some(3)));
+        assert!(!access.allows_nvr(&config, "10.0.0.50", Some(4)));
+        assert!(access.allows_nvr(&config, "10.0.0.60", Some(4)));
+        assert_eq!(
+            access.check_stream(&config, "lobby"),
+            Err(AuthError::StreamForbidden {
+                key: "security".to_string()
+            })
+        );
+        assert!(Access::All.allows_source(&config, "rtsp://10.0.0.10/live"));
+    }
+}
@@ -0,0 +1,256 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+
+use crate::config::{Config, Encoders};
+use crate::renditions::{LADDER_AUDIO_ENCODER, LADDER_VIDEO_ENCODER};
+
+/// How long one ffmpeg query may take.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Encoders tried, in order, when a profile's encoder isn't built into
+/// ffmpeg. Not every fallback makes the same stream: mpeg4 plays in VLC but
+/// not in browsers.
+const FALLBACKS: &[(&str, &[&str])] = &[
+    ("libx264", &["libopenh264", "mpeg4"]),
+    ("libx265", &["libx264", "libopenh264", "mpeg4"]),
+    ("libfdk_aac", &["aac"]),
+];
+
+/// What the installed ffmpeg can do, from `-version`, `-encoders` and
+/// `-protocols`.
+#[derive(Debug, Clone)]
+pub struct Capabilities {
+    pub version: String,
+    encoders: HashSet<String>,
+    /// Input protocols
+    protocols: HashSet<String>,
+}
+
+impl Capabilities {
+    pub async fn detect(ffmpeg: &Path) -> Result<Self, String> {
+        let version = query(ffmpeg, "-version").await?;
+        let version = parse_version(&version).ok_or_else(|| "Unrecognized ffmpeg -version output".to_string())?;
+        Ok(Self {
+            version,
+            encoders: parse_encoders(&query(ffmpeg, "-encoders").await?),
+            protocols: parse_protocols(&query(ffmpeg, "-protocols").await?),
+        })
+    }
+
+    /// An ffmpeg with just these encoders and the RTSP protocols.
+    #[cfg(test)]
+    pub fn with_encoders(encoders: &[&str]) -> Self {
+        Self {
+            version: "6.1".to_string(),
+            encoders: encoders.iter().map(|e| e.to_string()).collect(),
+            protocols: ["rtsp", "tcp", "udp"].iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    pub fn has_encoder(&self, encoder: &str) -> bool {
+        encoder == "copy" || self.encoders.contains(encoder)
+    }
+
+    /// `wanted` if ffmpeg has it, otherwise the first fallback it has.
+    fn choose(&self, wanted: &str) -> Option<String> {
+        if self.has_encoder(wanted) {
+            return Some(wanted.to_string());
+        }
+        let (_, fallbacks) = FALLBACKS.iter().find(|(encoder, _)| *encoder == wanted)?;
+        fallbacks
+            .iter()
+            .find(|encoder| self.has_encoder(encoder))
+            .map(|encoder| encoder.to_string())
+    }
+
+    /// Fit a loaded configuration to this ffmpeg: swap missing encoders for
+    /// fallbacks, and mark profiles and ladders that can't be encoded at all
+    /// so they are refused. Returns what was changed, for logging.
+    pub fn apply(&self, config: &mut Config) -> Vec<String> {
+        let mut warnings = Vec::new();
+        let mut unavailable = BTreeMap::new();
+
+        for (name, profile) in &mut config.profiles {
+            let mut missing = Vec::new();
+            match self.choose(&profile.video_codec) {
+                Some(encoder) if encoder != profile.video_codec => {
+                    warnings.push(format!(
+                        "profiles.{}: ffmpeg has no {} encoder, using {} instead",
+                        name, profile.video_codec, encoder
+                    ));
+                    // preset and tune only mean something to x264 and x265
+                    if !has_x264_options(&encoder) {
+                        profile.preset = None;
+                        profile.tune = None;
+                    }
+                    profile.video_codec = encoder;
+                }
+                Some(_) => {}
+                None => missing.push(profile.video_codec.clone()),
+            }
+            match self.choose(&profile.audio_codec) {
+                Some(encoder) if encoder != profile.audio_codec => {
+                    warnings.push(format!(
+                        "profiles.{}: ffmpeg has no {} encoder, using {} instead",
+                        name, profile.audio_codec, encoder
+                    ));
+                    profile.audio_codec = encoder;
+                }
+                Some(_) => {}
+                None => missing.push(profile.audio_codec.clone()),
+            }
+            if !missing.is_empty() {
+                let reason = format!("ffmpeg has no {} encoder", missing.join(" or "));
+                warnings.push(format!("profiles.{}: {}; streams using it won't start", name, reason));
+                unavailable.insert(name.clone(), reason);
+            }
+        }
+
+        let ladder = match self.choose(LADDER_VIDEO_ENCODER) {
+            Some(_) if !self.has_encoder(LADDER_AUDIO_ENCODER) => {
+                Err(format!("ffmpeg has no {} encoder", LADDER_AUDIO_ENCODER))
+            }
+            Some(encoder) => {
+                if encoder != LADDER_VIDEO_ENCODER {
+                    warnings.push(format!(
+                        "Rendition ladders: ffmpeg has no {} encoder, using {} instead",
+                        LADDER_VIDEO_ENCODER, encoder
+                    ));
+                }
+                Ok(encoder)
+            }
+            None => Err(format!("ffmpeg has no {} encoder", LADDER_VIDEO_ENCODER)),
+        };
+        if let Err(reason) = &ladder {
+            warnings.push(format!("Rendition ladders: {}; only source renditions will work", reason));
+        }
+
+        for scheme in &config.sources.allowed_schemes {
+            if !self.protocols.contains(&scheme.to_ascii_lowercase()) {
+                warnings.push(format!(
+                    "sources.allowed_schemes: ffmpeg can't open {} sources",
+                    scheme
+                ));
+            }
+        }
+
+        config.encoders = Encoders { ladder, unavailable };
+        warnings
+    }
+}
+
+/// Whether an encoder takes x264-style `-preset`, `-tune`, `-profile` and
+/// `-level` options.
+pub fn has_x264_options(encoder: &str) -> bool {
+    matches!(encoder, "libx264" | "libx265")
+}
+
+async fn query(ffmpeg: &Path, option: &str) -> Result<String, String> {
+    let output = Command::new(ffmpeg)
+        .args(["-hide_banner", option])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    match tokio::time::timeout(QUERY_TIMEOUT, output).await {
+        Ok(Ok(output)) if output.status.success() => Ok(String::from_utf8_lossy(&output.stdout).into_owned()),
+        Ok(Ok(output)) => Err(format!("{} {} exited with {}", ffmpeg.display(), option, output.status)),
+        Ok(Err(e)) => Err(format!("Failed to run {}: {}", ffmpeg.display(), e)),
+        Err(_) => Err(format!("{} {} timed out", ffmpeg.display(), option)),
+    }
+}
+
+/// `ffmpeg version 6.1.1-3ubuntu5 Copyright ...` -> `6.1.1-3ubuntu5`
+fn parse_version(output: &str) -> Option<String> {
+    let line = output.lines().next()?;
+    let version = line.strip_prefix("ffmpeg version ")?.split_whitespace().next()?;
+    Some(version.to_string())
+}
+
+/// Encoder names from `ffmpeg -encoders`, listed after a `------` line as
+/// ` V....D libx264   libx264 H.264 / AVC ...`.
+fn parse_encoders(output: &str) -> HashSet<String> {
+    output
+        .lines()
+        .skip_while(|line| line.trim() != "------")
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Input protocol names from `ffmpeg -protocols`: one per line between
+/// `Input:` and `Output:`.
+fn parse_protocols(output: &str) -> HashSet<String> {
+    output
+        .lines()
+        .map(str::trim)
+        .skip_while(|line| *line != "Input:")
+        .skip(1)
+        .take_while(|line| *line != "Output:")
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Profile;
+
+    #[test]
+    fn parses_ffmpeg_output() {
+        assert_eq!(
+            parse_version("ffmpeg version 6.1.1-3ubuntu5 Copyright (c) 2000-2023 the FFmpeg developers\nbuilt with gcc 13"),
+            Some("6.1.1-3ubuntu5".to_string())
+        );
+        assert_eq!(parse_version("avconv version 12"), None);
+
+        let encoders = parse_encoders(
+            "Encoders:\n V..... = Video\n A..... = Audio\n ------\n V....D libx264              libx264 H.264 / AVC\n A....D aac                  AAC (Advanced Audio Coding)\n",
+        );
+        assert_eq!(encoders, HashSet::from(["libx264".to_string(), "aac".to_string()]));
+
+        let protocols = parse_protocols("Supported file protocols:\nInput:\n  file\n  rtsp\nOutput:\n  file\n  rtmp\n");
+        assert_eq!(protocols, HashSet::from(["file".to_string(), "rtsp".to_string()]));
+    }
+
+    #[test]
+    fn falls_back_and_refuses_what_cannot_be_encoded() {
+        let mut config = Config::default();
+        config.profiles.insert(
+            "hevc".to_string(),
+            Profile {
+                video_codec: "libx265".to_string(),
+                ..Default::default()
+            },
+        );
+        config.profiles.insert(
+            "fdk".to_string(),
+            Profile {
+                audio_codec: "libfdk_aac".to_string(),
+                ..Default::default()
+            },
+        );
+
+        let warnings = Capabilities::with_encoders(&["libopenh264", "aac"]).apply(&mut config);
+        let default = &config.profiles["default"];
+        assert_eq!(default.video_codec, "libopenh264");
+        assert_eq!((default.preset.as_deref(), default.tune.as_deref()), (None, None));
+        assert_eq!(config.profiles["fdk"].audio_codec, "aac");
+        assert_eq!(config.profiles["hevc"].video_codec, "libopenh264");
+        assert_eq!(config.encoders.ladder.as_deref(), Ok("libopenh264"));
+        assert!(config.encoders.unavailable.is_empty());
+        assert!(warnings.contains(&"profiles.default: ffmpeg has no libx264 encoder, using libopenh264 instead".to_string()));
+        assert!(warnings.contains(&"sources.allowed_schemes: ffmpeg can't open rtsps sources".to_string()));
+
+        let mut config = Config::default();
+        Capabilities::with_encoders(&["aac"]).apply(&mut config);
+        assert_eq!(config.encoders.unavailable["default"], "ffmpeg has no libx264 encoder");
+        assert!(config.profile(None).is_err());
+        assert_eq!(config.encoders.ladder, Err("ffmpeg has no libx264 encoder".to_string()));
+    }
+}
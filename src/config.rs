@@ -0,0 +1,1177 @@
+use anyhow::{anyhow, Context, Result};
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+
+use crate::auth::{Role, Scope};
+use crate::credentials::{CredentialStore, Login};
+use crate::janitor;
+use crate::renditions;
+
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Top-level configuration file (`--config server.toml`).
+///
+/// Every section is optional; missing values fall back to the same defaults
+/// the server used before configuration files existed.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub logging: LoggingConfig,
+    pub health: HealthConfig,
+    pub tls: TlsConfig,
+    pub admin: AdminConfig,
+    pub cors: CorsConfig,
+    pub hls: HlsConfig,
+    pub ffmpeg: FfmpegConfig,
+    pub limits: LimitsConfig,
+    pub rate_limits: RateLimitsConfig,
+    pub auth: AuthConfig,
+    pub playback: PlaybackConfig,
+    pub credentials: CredentialsConfig,
+    pub sources: SourcesConfig,
+    pub profiles: BTreeMap<String, Profile>,
+    pub nvrs: BTreeMap<String, NvrConfig>,
+    pub streams: Vec<StreamConfig>,
+    /// Filled in from the installed ffmpeg by `Capabilities::apply`
+    #[serde(skip)]
+    pub encoders: Encoders,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    /// Profile used when a request or stream doesn't name one
+    pub default_profile: String,
+    /// JSON file where streams started through the API are persisted
+    pub registry_path: Option<PathBuf>,
+    /// How long viewers may keep playing after a shutdown signal
+    pub shutdown_grace_secs: u64,
+    /// Directory holding each server process's `rtsp-proxy-<pid>.pids` file
+    /// of running ffmpeg processes (default: `hls.temp_dir`)
+    pub pid_dir: Option<PathBuf>,
+}
+
+/// Log output. `filter` takes `RUST_LOG`-style directives and, when set,
+/// replaces `level`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// `error`, `warn`, `info`, `debug`, `trace` or `off`
+    pub level: String,
+    /// e.g. `info,rtsp_proxy::hls_session=debug`
+    pub filter: Option<String>,
+    pub format: LogFormat,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line, with the fields of the current span
+    Json,
+}
+
+impl LoggingConfig {
+    /// The filter directives this section asks for.
+    pub fn directives(&self) -> String {
+        self.filter.clone().unwrap_or_else(|| self.level.clone())
+    }
+}
+
+/// What `/readyz` requires.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    /// Free space needed under `hls.temp_dir`
+    pub min_free_disk_mb: u64,
+    /// How long the ffmpeg version and encoder check is reused
+    pub ffmpeg_check_interval_secs: u64,
+}
+
+/// HTTPS for the main listener. Enabled when both PEM files are set.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// Certificate chain, PEM
+    pub cert_path: Option<PathBuf>,
+    /// Private key, PEM
+    pub key_path: Option<PathBuf>,
+    /// How often to check the files for a renewed certificate (0 disables)
+    pub reload_interval_secs: u64,
+    /// Plain-HTTP port that redirects to HTTPS; no redirect listener while unset
+    pub redirect_http_port: Option<u16>,
+}
+
+/// A listener of its own for the control and admin routes, so they can be
+/// kept off the public playback listener.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// `host:port`, or `unix:<path>` for a Unix domain socket; the routes
+    /// stay on the public listener while unset
+    pub listen: Option<String>,
+    /// Permissions of the Unix domain socket
+    pub socket_mode: u32,
+}
+
+/// CORS per route group. Playback is open to any origin by default so
+/// players can be embedded anywhere; the control API is same-origin only.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Playback routes: `/stream...`, `/player`, `/api/hls/sessions...`,
+    /// `/proxy/rtsp` and `/proxyhl/rtsp`
+    pub playback: CorsPolicy,
+    /// Every other route
+    pub control: CorsPolicy,
+}
+
+/// Cross-origin requests a route group answers. Each list may be `["*"]`
+/// for any value, except together with `allow_credentials`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsPolicy {
+    /// Origins such as `https://portal.example.com`; none allows only same-origin requests
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    /// Let browsers send cookies and HTTP auth cross-origin
+    pub allow_credentials: bool,
+    /// How long browsers may cache a preflight response
+    pub max_age_secs: u64,
+}
+
+/// Where a listener binds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HlsConfig {
+    /// Directory under which per-session `hls-*` directories are created
+    pub temp_dir: PathBuf,
+    pub idle_timeout_secs: u64,
+    pub segment_duration_secs: u32,
+    pub list_size: u32,
+    /// How often orphaned session directories are swept; 0 disables the sweep
+    pub janitor_interval_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FfmpegConfig {
+    /// ffmpeg executable, looked up in `PATH` unless it contains a `/`
+    pub path: PathBuf,
+    pub ffprobe_path: PathBuf,
+    pub rtsp_transport: String,
+    /// Extra arguments inserted before `-i`
+    pub input_args: Vec<String>,
+    /// Protocols ffmpeg may open, passed as `-protocol_whitelist`. Must cover
+    /// `sources.allowed_schemes` and the transports they run over.
+    pub protocol_whitelist: Vec<String>,
+}
+
+/// Caps on concurrent ffmpeg processes; 0 means unlimited.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// ffmpeg processes across the whole server
+    pub max_transcodes: usize,
+    /// Connections to one camera or NVR host, to stay within its session limit
+    pub max_per_source_host: usize,
+    /// Sessions started by one client address
+    pub max_per_client: usize,
+    /// Requests that may wait for a free slot; beyond that they get a 503
+    pub queue_size: usize,
+    /// How long a queued request waits before getting a 503
+    pub queue_timeout_secs: u64,
+    /// `Retry-After` sent with the 503
+    pub retry_after_secs: u64,
+}
+
+/// Request rate limits per client, keyed by API key or else client address.
+/// Each budget is a token bucket; all are unlimited by default.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitsConfig {
+    /// Requests that start ffmpeg: `/stream`, `/stream/hls`,
+    /// `POST /api/hls/sessions` and `POST /api/stream/{id}/start`
+    pub sessions: RateBudget,
+    /// Playlist and segment fetches
+    pub segments: RateBudget,
+    /// The Hikvision routes
+    pub nvr: RateBudget,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateBudget {
+    /// Sustained rate; 0 is unlimited
+    pub per_minute: u32,
+    /// Requests allowed at once before the rate applies (default: `per_minute`)
+    pub burst: Option<u32>,
+}
+
+/// API key authentication. Disabled by default, in which case every route
+/// is open.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub enabled: bool,
+    pub keys: Vec<ApiKeyConfig>,
+}
+
+/// A key accepted in `X-API-Key` or `Authorization: Bearer`.
+///
+/// A key may use the routes of its role plus any extra `scopes`. Listing
+/// `streams` or `groups` limits it to those streams and camera groups;
+/// otherwise it may touch every stream.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKeyConfig {
+    /// Label used in logs; the key itself is never logged
+    pub name: String,
+    pub key: String,
+    pub role: Option<Role>,
+    #[serde(default)]
+    pub scopes: Vec<Scope>,
+    /// Stream ids this key may access
+    #[serde(default)]
+    pub streams: Vec<String>,
+    /// Camera groups, as tagged on `[[streams]]` and `[nvrs]`, this key may access
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+/// HMAC-signed playback URLs, for handing out playback without API keys.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PlaybackConfig {
+    /// Signing secret; signed URLs are disabled while unset
+    pub signing_secret: Option<String>,
+    /// Lifetime of the tokens the server hands out
+    pub token_ttl_secs: u64,
+    /// Bind handed-out tokens to the address of the client they were issued to
+    pub bind_ip: bool,
+}
+
+/// Encrypted store of camera logins, referenced as `credential=<name>`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CredentialsConfig {
+    /// Store file; the store is disabled while unset
+    pub path: Option<PathBuf>,
+    /// Environment variable holding the base64-encoded 32-byte master key
+    pub master_key_env: String,
+    /// File holding the master key, read when the variable is unset
+    pub master_key_file: Option<PathBuf>,
+}
+
+/// Where user-supplied source URLs and NVR addresses may point. Streams and
+/// NVRs from this file are trusted and not checked.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SourcesConfig {
+    /// URL schemes accepted in `rtsp_url` parameters
+    pub allowed_schemes: Vec<String>,
+    /// Networks sources may be in; any network while empty
+    pub allow: Vec<IpNet>,
+    /// Networks sources may never be in, checked before `allow`
+    pub deny: Vec<IpNet>,
+}
+
+/// Encoder choices that depend on the installed ffmpeg rather than the file.
+#[derive(Debug, Clone)]
+pub struct Encoders {
+    /// Video encoder of transcoded ladder variants, or why there is none
+    pub ladder: Result<String, String>,
+    /// Profiles ffmpeg can't encode, with the reason
+    pub unavailable: BTreeMap<String, String>,
+}
+
+/// Named transcoding profile.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    pub video_codec: String,
+    pub preset: Option<String>,
+    pub tune: Option<String>,
+    pub video_bitrate_kbps: u32,
+    /// Keyframe interval in frames
+    pub gop: u32,
+    pub audio_codec: String,
+    pub audio_bitrate_kbps: u32,
+    pub audio_sample_rate: u32,
+    /// Default HLS rendition ladder, e.g. `["source", "720p", "360p"]`
+    pub renditions: Vec<String>,
+    /// Extra output arguments appended after the codec settings
+    pub extra_args: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NvrConfig {
+    pub host: String,
+    pub rtsp_port: u16,
+    pub http_port: u16,
+    pub username: String,
+    pub password: String,
+    /// Name of a stored login to use instead of `username`/`password`
+    pub credential: Option<String>,
+    /// Camera groups; keys limited to one of them may use the Hikvision
+    /// proxy routes against this NVR
+    pub groups: Vec<String>,
+}
+
+/// A managed stream started at boot.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StreamConfig {
+    pub id: String,
+    /// Full RTSP URL; mutually exclusive with `nvr`
+    pub url: Option<String>,
+    /// Name of an entry in `[nvrs]`
+    pub nvr: Option<String>,
+    #[serde(default = "default_channel")]
+    pub channel: u32,
+    #[serde(default = "default_channel")]
+    pub stream_number: u32,
+    pub profile: Option<String>,
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Camera groups, for limiting API keys to a set of streams
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+/// Handle to the live configuration, swapped atomically on reload.
+///
+/// Readers take a cheap snapshot with [`SharedConfig::current`], so a request
+/// keeps seeing one consistent configuration even if a reload happens
+/// mid-flight.
+#[derive(Clone)]
+pub struct SharedConfig(Arc<RwLock<Arc<Config>>>);
+
+impl SharedConfig {
+    pub fn new(config: Arc<Config>) -> Self {
+        Self(Arc::new(RwLock::new(config)))
+    }
+
+    pub fn current(&self) -> Arc<Config> {
+        self.0.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn replace(&self, config: Arc<Config>) {
+        *self.0.write().unwrap_or_else(|e| e.into_inner()) = config;
+    }
+}
+
+fn default_channel() -> u32 {
+    1
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let mut profiles = BTreeMap::new();
+        profiles.insert(DEFAULT_PROFILE.to_string(), Profile::default());
+        Self {
+            server: ServerConfig::default(),
+            logging: LoggingConfig::default(),
+            health: HealthConfig::default(),
+            tls: TlsConfig::default(),
+            admin: AdminConfig::default(),
+            cors: CorsConfig::default(),
+            hls: HlsConfig::default(),
+            ffmpeg: FfmpegConfig::default(),
+            limits: LimitsConfig::default(),
+            rate_limits: RateLimitsConfig::default(),
+            auth: AuthConfig::default(),
+            playback: PlaybackConfig::default(),
+            credentials: CredentialsConfig::default(),
+            sources: SourcesConfig::default(),
+            profiles,
+            nvrs: BTreeMap::new(),
+            streams: Vec::new(),
+            encoders: Encoders::default(),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: "0.0.0.0".to_string(),
+            port: 5000,
+            default_profile: DEFAULT_PROFILE.to_string(),
+            registry_path: None,
+            shutdown_grace_secs: 10,
+            pid_dir: None,
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            filter: None,
+            format: LogFormat::Text,
+        }
+    }
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            min_free_disk_mb: 512,
+            ffmpeg_check_interval_secs: 60,
+        }
+    }
+}
+
+impl Default for HlsConfig {
+    fn default() -> Self {
+        Self {
+            temp_dir: PathBuf::from("/tmp"),
+            idle_timeout_secs: 60,
+            segment_duration_secs: 2,
+            list_size: 5,
+            janitor_interval_secs: 300,
+        }
+    }
+}
+
+impl Default for FfmpegConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("ffmpeg"),
+            ffprobe_path: PathBuf::from("ffprobe"),
+            rtsp_transport: "tcp".to_string(),
+            input_args: Vec::new(),
+            protocol_whitelist: ["rtsp", "rtsps", "rtp", "srtp", "tcp", "udp", "tls", "http"]
+                .map(String::from)
+                .to_vec(),
+        }
+    }
+}
+
+impl Encoders {
+    /// The ladder video encoder; only meaningful when ladders are available.
+    pub fn ladder_encoder(&self) -> &str {
+        self.ladder.as_deref().unwrap_or(renditions::LADDER_VIDEO_ENCODER)
+    }
+}
+
+impl Default for Encoders {
+    fn default() -> Self {
+        Self {
+            ladder: Ok(renditions::LADDER_VIDEO_ENCODER.to_string()),
+            unavailable: BTreeMap::new(),
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_transcodes: 0,
+            max_per_source_host: 0,
+            max_per_client: 0,
+            queue_size: 0,
+            queue_timeout_secs: 10,
+            retry_after_secs: 5,
+        }
+    }
+}
+
+impl Default for PlaybackConfig {
+    fn default() -> Self {
+        Self {
+            signing_secret: None,
+            token_ttl_secs: 3600,
+            bind_ip: false,
+        }
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            cert_path: None,
+            key_path: None,
+            reload_interval_secs: 60,
+            redirect_http_port: None,
+        }
+    }
+}
+
+impl TlsConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.cert_path.is_some() && self.key_path.is_some()
+    }
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            playback: CorsPolicy {
+                allowed_origins: vec!["*".to_string()],
+                ..CorsPolicy::default()
+            },
+            control: CorsPolicy::default(),
+        }
+    }
+}
+
+impl Default for CorsPolicy {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_methods: ["GET", "HEAD", "POST", "PUT", "DELETE"].map(String::from).to_vec(),
+            allowed_headers: ["content-type", "authorization", "x-api-key", "range"].map(String::from).to_vec(),
+            allow_credentials: false,
+            max_age_secs: 600,
+        }
+    }
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            listen: None,
+            socket_mode: 0o660,
+        }
+    }
+}
+
+impl AdminConfig {
+    pub fn listen_addr(&self) -> Option<ListenAddr> {
+        let listen = self.listen.as_deref()?;
+        Some(match listen.strip_prefix("unix:") {
+            Some(path) => ListenAddr::Unix(PathBuf::from(path)),
+            None => ListenAddr::Tcp(listen.to_string()),
+        })
+    }
+}
+
+impl Default for SourcesConfig {
+    fn default() -> Self {
+        Self {
+            allowed_schemes: vec!["rtsp".to_string(), "rtsps".to_string()],
+            allow: Vec::new(),
+            // Loopback, link-local (cloud metadata services) and unspecified
+            deny: ["127.0.0.0/8", "169.254.0.0/16", "0.0.0.0/8", "::1/128", "fe80::/10", "::/128"]
+                .map(|net| net.parse().expect("valid network"))
+                .to_vec(),
+        }
+    }
+}
+
+impl Default for CredentialsConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            master_key_env: "RTSP_PROXY_MASTER_KEY".to_string(),
+            master_key_file: None,
+        }
+    }
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            video_codec: "libx264".to_string(),
+            preset: Some("ultrafast".to_string()),
+            tune: Some("zerolatency".to_string()),
+            video_bitrate_kbps: 2000,
+            gop: 50,
+            audio_codec: "aac".to_string(),
+            audio_bitrate_kbps: 128,
+            audio_sample_rate: 44100,
+            renditions: Vec::new(),
+            extra_args: Vec::new(),
+        }
+    }
+}
+
+impl Default for NvrConfig {
+    fn default() -> Self {
+        Self {
+            host: String::new(),
+            rtsp_port: 554,
+            http_port: 80,
+            username: "admin".to_string(),
+            password: String::new(),
+            credential: None,
+            groups: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Read, parse and validate a TOML configuration file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        let mut config: Config = toml::from_str(&text)
+            .map_err(|e| anyhow!("Failed to parse config file {}: {}", path.display(), e))?;
+
+        // The built-in default profile is always available unless overridden
+        config
+            .profiles
+            .entry(DEFAULT_PROFILE.to_string())
+            .or_default();
+
+        config
+            .validate()
+            .map_err(|e| anyhow!("Invalid config file {}:\n{}", path.display(), e))?;
+        Ok(config)
+    }
+
+    /// Check cross-references and value ranges, reporting every problem at once.
+    pub fn validate(&self) -> Result<()> {
+        let mut errors: Vec<String> = Vec::new();
+
+        if self.server.port == 0 {
+            errors.push("server.port must be between 1 and 65535".to_string());
+        }
+        if !self.profiles.contains_key(&self.server.default_profile) {
+            errors.push(format!(
+                "server.default_profile: unknown profile \"{}\"",
+                self.server.default_profile
+            ));
+        }
+
+        if self.logging.level.parse::<LevelFilter>().is_err() {
+            errors.push(format!(
+                "logging.level: unknown level \"{}\"; expected error, warn, info, debug, trace or off",
+                self.logging.level
+            ));
+        }
+        if let Some(filter) = &self.logging.filter {
+            if let Err(e) = EnvFilter::try_new(filter) {
+                errors.push(format!("logging.filter: {}", e));
+            }
+        }
+
+        if self.tls.cert_path.is_some() != self.tls.key_path.is_some() {
+            errors.push("tls.cert_path and tls.key_path must be set together".to_string());
+        }
+        match self.tls.redirect_http_port {
+            Some(_) if !self.tls.is_enabled() => {
+                errors.push("tls.redirect_http_port requires tls.cert_path and tls.key_path".to_string());
+            }
+            Some(0) => errors.push("tls.redirect_http_port must be between 1 and 65535".to_string()),
+            Some(port) if port == self.server.port => {
+                errors.push("tls.redirect_http_port must differ from server.port".to_string());
+            }
+            _ => {}
+        }
+
+        match self.admin.listen_addr() {
+            Some(ListenAddr::Unix(path)) if path.as_os_str().is_empty() => {
+                errors.push("admin.listen: unix: needs a socket path".to_string());
+            }
+            Some(ListenAddr::Unix(_)) if !cfg!(unix) => {
+                errors.push("admin.listen: Unix domain sockets are not supported on this platform".to_string());
+            }
+            Some(ListenAddr::Tcp(addr)) if addr.rsplit_once(':').is_none_or(|(_, port)| port.parse::<u16>().is_err()) => {
+                errors.push(format!("admin.listen: expected host:port or unix:<path>, got \"{}\"", addr));
+            }
+            _ => {}
+        }
+        if self.admin.socket_mode > 0o777 {
+            errors.push("admin.socket_mode must be a permission mode such as 0o660".to_string());
+        }
+
+        for (group, policy) in [("playback", &self.cors.playback), ("control", &self.cors.control)] {
+            for origin in policy.allowed_origins.iter().filter(|o| *o != "*") {
+                let valid = url::Url::parse(origin).is_ok_and(|url| {
+                    matches!(url.scheme(), "http" | "https") && url.origin().ascii_serialization() == *origin
+                });
+                if !valid {
+                    errors.push(format!(
+                        "cors.{}.allowed_origins: \"{}\" is not an origin like https://portal.example.com",
+                        group, origin
+                    ));
+                }
+            }
+            for method in policy.allowed_methods.iter().filter(|m| *m != "*") {
+                if axum::http::Method::from_bytes(method.as_bytes()).is_err() {
+                    errors.push(format!("cors.{}.allowed_methods: invalid method \"{}\"", group, method));
+                }
+            }
+            for header in policy.allowed_headers.iter().filter(|h| *h != "*") {
+                if axum::http::HeaderName::from_bytes(header.as_bytes()).is_err() {
+                    errors.push(format!("cors.{}.allowed_headers: invalid header \"{}\"", group, header));
+                }
+            }
+            let wildcard = [&policy.allowed_origins, &policy.allowed_methods, &policy.allowed_headers]
+                .iter()
+                .any(|list| list.iter().any(|v| v == "*"));
+            if policy.allow_credentials && wildcard {
+                errors.push(format!("cors.{}: allow_credentials can't be combined with \"*\"", group));
+            }
+        }
+
+        if self.hls.idle_timeout_secs == 0 {
+            errors.push("hls.idle_timeout_secs must be greater than 0".to_string());
+        }
+        if self.hls.segment_duration_secs == 0 {
+            errors.push("hls.segment_duration_secs must be greater than 0".to_string());
+        }
+        if self.hls.list_size == 0 {
+            errors.push("hls.list_size must be greater than 0".to_string());
+        }
+
+        if !matches!(self.ffmpeg.rtsp_transport.as_str(), "tcp" | "udp" | "http" | "udp_multicast") {
+            errors.push(format!(
+                "ffmpeg.rtsp_transport: expected tcp, udp, http or udp_multicast, got \"{}\"",
+                self.ffmpeg.rtsp_transport
+            ));
+        }
+
+        if self.ffmpeg.path.as_os_str().is_empty() {
+            errors.push("ffmpeg.path must not be empty".to_string());
+        }
+        if self.ffmpeg.ffprobe_path.as_os_str().is_empty() {
+            errors.push("ffmpeg.ffprobe_path must not be empty".to_string());
+        }
+        if self.ffmpeg.protocol_whitelist.is_empty() {
+            errors.push("ffmpeg.protocol_whitelist must not be empty".to_string());
+        }
+        if self.sources.allowed_schemes.is_empty() {
+            errors.push("sources.allowed_schemes must not be empty".to_string());
+        }
+        for scheme in &self.sources.allowed_schemes {
+            if !self.ffmpeg.protocol_whitelist.iter().any(|p| p.eq_ignore_ascii_case(scheme)) {
+                errors.push(format!(
+                    "sources.allowed_schemes: \"{}\" is missing from ffmpeg.protocol_whitelist",
+                    scheme
+                ));
+            }
+        }
+
+        if self.limits.queue_size > 0 && self.limits.queue_timeout_secs == 0 {
+            errors.push("limits.queue_timeout_secs must be greater than 0 when limits.queue_size is set".to_string());
+        }
+
+        let budgets = [
+            ("sessions", &self.rate_limits.sessions),
+            ("segments", &self.rate_limits.segments),
+            ("nvr", &self.rate_limits.nvr),
+        ];
+        for (name, budget) in budgets {
+            if budget.burst == Some(0) {
+                errors.push(format!("rate_limits.{}.burst must be greater than 0", name));
+            }
+        }
+
+        if self.auth.enabled && self.auth.keys.is_empty() {
+            errors.push("auth.enabled requires at least one entry in auth.keys".to_string());
+        }
+        let mut seen_keys = HashSet::new();
+        for (i, key) in self.auth.keys.iter().enumerate() {
+            if key.name.is_empty() {
+                errors.push(format!("auth.keys[{}]: name must not be empty", i));
+            }
+            if key.key.len() < 16 {
+                errors.push(format!("auth.keys[{}]: key must be at least 16 characters", i));
+            } else if !seen_keys.insert(key.key.as_str()) {
+                errors.push(format!("auth.keys[{}]: duplicate key", i));
+            }
+            if key.role.is_none() && key.scopes.is_empty() {
+                errors.push(format!("auth.keys[{}]: set a role or at least one scope", i));
+            }
+            for group in &key.groups {
+                if !self.has_group(group) {
+                    errors.push(format!("auth.keys[{}]: unknown group \"{}\"", i, group));
+                }
+            }
+        }
+
+        if self.playback.signing_secret.as_ref().is_some_and(|secret| secret.len() < 32) {
+            errors.push("playback.signing_secret must be at least 32 characters".to_string());
+        }
+        if self.playback.token_ttl_secs == 0 {
+            errors.push("playback.token_ttl_secs must be greater than 0".to_string());
+        }
+
+        if self.credentials.path.is_some() && self.credentials.master_key_env.is_empty() {
+            errors.push("credentials.master_key_env must not be empty".to_string());
+        }
+
+        for (name, profile) in &self.profiles {
+            if profile.video_codec.is_empty() {
+                errors.push(format!("profiles.{}.video_codec must not be empty", name));
+            }
+            if profile.video_bitrate_kbps == 0 {
+                errors.push(format!("profiles.{}.video_bitrate_kbps must be greater than 0", name));
+            }
+            if profile.gop == 0 {
+                errors.push(format!("profiles.{}.gop must be greater than 0", name));
+            }
+            if let Err(e) = renditions::parse_ladder(&profile.renditions.join(",")) {
+                errors.push(format!("profiles.{}.renditions: {}", name, e));
+            }
+        }
+
+        for (name, nvr) in &self.nvrs {
+            if nvr.host.is_empty() {
+                errors.push(format!("nvrs.{}.host must not be empty", name));
+            }
+            if nvr.credential.is_some() && !nvr.password.is_empty() {
+                errors.push(format!("nvrs.{}: set either credential or password, not both", name));
+            }
+        }
+
+        let mut seen_ids = HashSet::new();
+        for (i, stream) in self.streams.iter().enumerate() {
+            let label = if stream.id.is_empty() {
+                format!("streams[{}]", i)
+            } else {
+                format!("streams[{}] (id \"{}\")", i, stream.id)
+            };
+
+            if stream.id.is_empty() {
+                errors.push(format!("{}: id must not be empty", label));
+            } else if !seen_ids.insert(stream.id.as_str()) {
+                errors.push(format!("{}: duplicate stream id", label));
+            }
+
+            match (&stream.url, &stream.nvr) {
+                (Some(_), Some(_)) => {
+                    errors.push(format!("{}: set either url or nvr, not both", label));
+                }
+                (None, None) => errors.push(format!("{}: one of url or nvr is required", label)),
+                (Some(url), None) => {
+                    if let Err(e) = url::Url::parse(url) {
+                        errors.push(format!("{}: invalid url: {}", label, e));
+                    } else if !(url.starts_with("rtsp://") || url.starts_with("rtsps://")) {
+                        errors.push(format!("{}: url must start with rtsp:// or rtsps://", label));
+                    }
+                }
+                (None, Some(nvr)) => {
+                    if !self.nvrs.contains_key(nvr) {
+                        errors.push(format!("{}: unknown nvr \"{}\"", label, nvr));
+                    }
+                }
+            }
+
+            if let Some(profile) = &stream.profile {
+                if !self.profiles.contains_key(profile) {
+                    errors.push(format!("{}: unknown profile \"{}\"", label, profile));
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "{}",
+                errors.iter().map(|e| format!("  - {}", e)).collect::<Vec<_>>().join("\n")
+            ))
+        }
+    }
+
+    /// Whether any stream or NVR is tagged with `group`.
+    fn has_group(&self, group: &str) -> bool {
+        let tagged = |groups: &[String]| groups.iter().any(|g| g == group);
+        self.streams.iter().any(|s| tagged(&s.groups)) || self.nvrs.values().any(|n| tagged(&n.groups))
+    }
+
+    /// Look up a profile by name, falling back to the configured default.
+    /// Profiles the installed ffmpeg can't encode are refused.
+    pub fn profile(&self, name: Option<&str>) -> Result<&Profile> {
+        let name = name.unwrap_or(&self.server.default_profile);
+        let profile = self
+            .profiles
+            .get(name)
+            .ok_or_else(|| anyhow!("Unknown profile \"{}\"", name))?;
+        match self.encoders.unavailable.get(name) {
+            Some(reason) => Err(anyhow!("Profile \"{}\" can't be used: {}", name, reason)),
+            None => Ok(profile),
+        }
+    }
+
+    /// Resolve the RTSP URL of a configured stream, with its NVR's login
+    /// looked up in `credentials` where needed.
+    pub fn stream_url(&self, stream: &StreamConfig, credentials: &CredentialStore) -> Result<String> {
+        match (&stream.url, &stream.nvr) {
+            (Some(url), _) => Ok(url.clone()),
+            (None, Some(name)) => {
+                let nvr = self.nvr(name)?;
+                let login = credentials.nvr_login(nvr)?;
+                Ok(nvr.rtsp_url(Some(&login), stream.channel, stream.stream_number))
+            }
+            (None, None) => Err(anyhow!("Stream {} has neither url nor nvr", stream.id)),
+        }
+    }
+
+    /// The RTSP URL of a configured stream without any NVR login, for telling
+    /// sources apart.
+    pub fn stream_source(&self, stream: &StreamConfig) -> Result<String> {
+        match (&stream.url, &stream.nvr) {
+            (Some(url), _) => Ok(url.clone()),
+            (None, Some(name)) => Ok(self.nvr(name)?.rtsp_url(None, stream.channel, stream.stream_number)),
+            (None, None) => Err(anyhow!("Stream {} has neither url nor nvr", stream.id)),
+        }
+    }
+
+    fn nvr(&self, name: &str) -> Result<&NvrConfig> {
+        self.nvrs
+            .get(name)
+            .ok_or_else(|| anyhow!("Unknown nvr \"{}\"", name))
+    }
+
+    pub fn hls_idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.hls.idle_timeout_secs)
+    }
+
+    pub fn pid_dir(&self) -> PathBuf {
+        self.server.pid_dir.clone().unwrap_or_else(|| self.hls.temp_dir.clone())
+    }
+
+    /// This process's pid file.
+    pub fn pid_file(&self) -> PathBuf {
+        janitor::pid_file(&self.pid_dir(), std::process::id())
+    }
+
+    /// Directory for an HLS session, e.g. `/tmp/hls-<server pid>-stream-<id>`.
+    pub fn hls_session_dir(&self, kind: &str, id: &str) -> String {
+        let prefix = janitor::session_dir_prefix(std::process::id());
+        self.hls
+            .temp_dir
+            .join(format!("{}{}-{}", prefix, kind, id))
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+impl FfmpegConfig {
+    /// Arguments up to and including `-i <url>`.
+    pub fn input_args(&self, rtsp_url: &str) -> Vec<String> {
+        let mut args = vec![
+            "-protocol_whitelist".to_string(),
+            self.protocol_whitelist.join(","),
+            "-rtsp_transport".to_string(),
+            self.rtsp_transport.clone(),
+        ];
+        args.extend(self.input_args.iter().cloned());
+        args.extend(["-i".to_string(), rtsp_url.to_string()]);
+        args
+    }
+}
+
+impl Profile {
+    /// Video and audio encoder arguments.
+    pub fn codec_args(&self) -> Vec<String> {
+        let mut args = vec!["-codec:v".to_string(), self.video_codec.clone()];
+        if let Some(preset) = &self.preset {
+            args.extend(["-preset".to_string(), preset.clone()]);
+        }
+        if let Some(tune) = &self.tune {
+            args.extend(["-tune".to_string(), tune.clone()]);
+        }
+        args.extend([
+            "-b:v".to_string(),
+            format!("{}k", self.video_bitrate_kbps),
+            "-codec:a".to_string(),
+            self.audio_codec.clone(),
+            "-ar".to_string(),
+            self.audio_sample_rate.to_string(),
+            "-b:a".to_string(),
+            format!("{}k", self.audio_bitrate_kbps),
+        ]);
+        args.extend(self.extra_args.iter().cloned());
+        args
+    }
+
+    /// Keyframe placement needed for clean HLS segment boundaries.
+    pub fn keyframe_args(&self) -> Vec<String> {
+        vec![
+            "-g".to_string(),
+            self.gop.to_string(),
+            "-keyint_min".to_string(),
+            (self.gop / 2).max(1).to_string(),
+            "-sc_threshold".to_string(),
+            "0".to_string(),
+        ]
+    }
+}
+
+impl NvrConfig {
+    pub fn rtsp_url(&self, login: Option<&Login>, channel: u32, stream_number: u32) -> String {
+        hikvision_rtsp_url(login, &self.host, self.rtsp_port, channel, stream_number)
+    }
+}
+
+/// Hikvision convention: `/ISAPI/Streaming/channels/{channel}{stream:02}`,
+/// where stream 1 is the main stream and 2 the sub stream.
+pub fn hikvision_rtsp_url(login: Option<&Login>, host: &str, port: u16, channel: u32, stream_number: u32) -> String {
+    format!(
+        "rtsp://{}{}:{}/ISAPI/Streaming/channels/{}{:02}",
+        login.map(Login::userinfo).unwrap_or_default(),
+        host,
+        port,
+        channel,
+        stream_number
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse `toml` as a config file would be, and return the validation
+    /// errors.
+    fn errors(toml: &str) -> Vec<String> {
+        let mut config: Config = toml::from_str(toml).expect("valid TOML");
+        config.profiles.entry(DEFAULT_PROFILE.to_string()).or_default();
+        match config.validate() {
+            Ok(()) => Vec::new(),
+            Err(e) => e.to_string().lines().map(|line| line.trim_start_matches("  - ").to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn default_config_is_valid() {
+        assert!(Config::default().validate().is_ok());
+        assert_eq!(errors(""), Vec::<String>::new());
+    }
+
+    #[test]
+    fn reports_every_problem_at_once() {
+        let errors = errors(
+            r#"
+            [server]
+            port = 0
+            default_profile = "missing"
+
+            [logging]
+            level = "loud"
+
+            [tls]
+            cert_path = "cert.pem"
+            redirect_http_port = 80
+
+            [admin]
+            listen = "localhost"
+
+            [cors.playback]
+            allowed_origins = ["*", "https://portal.example.com/app"]
+            allow_credentials = true
+
+            [hls]
+            idle_timeout_secs = 0
+            list_size = 0
+
+            [ffmpeg]
+            path = ""
+            rtsp_transport = "quic"
+            protocol_whitelist = ["rtsp", "tcp"]
+
+            [sources]
+            allowed_schemes = ["rtsp", "rtsps"]
+
+            [limits]
+            queue_size = 4
+            queue_timeout_secs = 0
+
+            [auth]
+            enabled = true
+
+            [[auth.keys]]
+            name = ""
+            key = "short"
+            groups = ["lobby"]
+
+            [playback]
+            signing_secret = "too-short"
+            "#,
+        );
+        assert_eq!(
+            errors,
+            [
+                "server.port must be between 1 and 65535",
+                "server.default_profile: unknown profile \"missing\"",
+                "logging.level: unknown level \"loud\"; expected error, warn, info, debug, trace or off",
+                "tls.cert_path and tls.key_path must be set together",
+                "tls.redirect_http_port requires tls.cert_path and tls.key_path",
+                "admin.listen: expected host:port or unix:<path>, got \"localhost\"",
+                "cors.playback.allowed_origins: \"https://portal.example.com/app\" is not an origin like https://portal.example.com",
+                "cors.playback: allow_credentials can't be combined with \"*\"",
+                "hls.idle_timeout_secs must be greater than 0",
+                "hls.list_size must be greater than 0",
+                "ffmpeg.rtsp_transport: expected tcp, udp, http or udp_multicast, got \"quic\"",
+                "ffmpeg.path must not be empty",
+                "sources.allowed_schemes: \"rtsps\" is missing from ffmpeg.protocol_whitelist",
+                "limits.queue_timeout_secs must be greater than 0 when limits.queue_size is set",
+                "auth.keys[0]: name must not be empty",
+                "auth.keys[0]: key must be at least 16 characters",
+                "auth.keys[0]: set a role or at least one scope",
+                "auth.keys[0]: unknown group \"lobby\"",
+                "playback.signing_secret must be at least 32 characters",
+            ]
+        );
+    }
+
+    #[test]
+    fn checks_profiles_nvrs_and_streams() {
+        let errors = errors(
+            r#"
+            [profiles.broken]
+            video_codec = ""
+            gop = 0
+            renditions = ["source", "4k"]
+
+            [nvrs.lobby]
+            host = ""
+
+            [[streams]]
+            id = "a"
+            url = "http://10.0.0.1/live"
+
+            [[streams]]
+            id = "a"
+            nvr = "garage"
+            profile = "missing"
+
+            [[streams]]
+            id = ""
+            "#,
+        );
+        assert_eq!(
+            errors,
+            [
+                "profiles.broken.video_codec must not be empty",
+                "profiles.broken.gop must be greater than 0",
+                "profiles.broken.renditions: Unknown rendition '4k'; expected one of source, 1080p, 720p, 480p, 360p, 240p",
+                "nvrs.lobby.host must not be empty",
+                "streams[0] (id \"a\"): url must start with rtsp:// or rtsps://",
+                "streams[1] (id \"a\"): duplicate stream id",
+                "streams[1] (id \"a\"): unknown nvr \"garage\"",
+                "streams[1] (id \"a\"): unknown profile \"missing\"",
+                "streams[2]: id must not be empty",
+                "streams[2]: one of url or nvr is required",
+            ]
+        );
+    }
+}
@@ -0,0 +1,48 @@
+use axum::http::{HeaderName, HeaderValue, Method};
+use std::time::Duration;
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer};
+
+use crate::config::CorsPolicy;
+
+impl CorsPolicy {
+    /// The layer enforcing this policy. Values that don't parse were
+    /// rejected by `Config::validate`.
+    pub fn layer(&self) -> CorsLayer {
+        let any = |list: &[String]| list.iter().any(|v| v == "*");
+
+        let origins = if any(&self.allowed_origins) {
+            AllowOrigin::any()
+        } else {
+            AllowOrigin::list(
+                self.allowed_origins
+                    .iter()
+                    .filter_map(|o| HeaderValue::from_str(o).ok()),
+            )
+        };
+        let methods = if any(&self.allowed_methods) {
+            AllowMethods::any()
+        } else {
+            AllowMethods::list(
+                self.allowed_methods
+                    .iter()
+                    .filter_map(|m| Method::from_bytes(m.as_bytes()).ok()),
+            )
+        };
+        let headers = if any(&self.allowed_headers) {
+            AllowHeaders::any()
+        } else {
+            AllowHeaders::list(
+                self.allowed_headers
+                    .iter()
+                    .filter_map(|h| HeaderName::from_bytes(h.as_bytes()).ok()),
+            )
+        };
+
+        CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods(methods)
+            .allow_headers(headers)
+            .allow_credentials(self.allow_credentials)
+            .max_age(Duration::from_secs(self.max_age_secs))
+    }
+}
@@ -0,0 +1,333 @@
+use anyhow::{anyhow, Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use thiserror::Error;
+use tokio::sync::Mutex;
+
+use crate::atomic_file;
+use crate::config::{CredentialsConfig, NvrConfig};
+
+const STORE_VERSION: u32 = 1;
+const MASTER_KEY_LEN: usize = 32;
+
+/// A camera or NVR login.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Login {
+    pub username: String,
+    pub password: String,
+}
+
+impl Login {
+    /// `user:password@`, percent-encoded for the authority part of a URL.
+    pub fn userinfo(&self) -> String {
+        format!(
+            "{}:{}@",
+            urlencoding::encode(&self.username),
+            urlencoding::encode(&self.password)
+        )
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum CredentialError {
+    #[error("The credential store is not configured; set credentials.path")]
+    NotConfigured,
+    #[error("Unknown credential \"{0}\"")]
+    Unknown(String),
+    #[error("Credential \"{0}\" could not be decrypted")]
+    Corrupt(String),
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoreFile {
+    version: u32,
+    /// Name to base64 `nonce || ciphertext`
+    credentials: BTreeMap<String, String>,
+}
+
+/// Named camera logins, so requests can say `credential=<name>` instead of
+/// carrying a password in the URL.
+///
+/// Each entry is sealed with AES-256-GCM under the master key, with its name
+/// as associated data so entries can't be swapped between names. Entries stay
+/// sealed in memory and are only opened when building an RTSP or ISAPI URL.
+/// The file is replaced atomically.
+#[derive(Clone)]
+pub struct CredentialStore {
+    inner: Option<Arc<Inner>>,
+}
+
+struct Inner {
+    path: PathBuf,
+    key: LessSafeKey,
+    entries: RwLock<BTreeMap<String, String>>,
+    /// Serializes writers so concurrent updates aren't lost
+    writing: Mutex<()>,
+}
+
+impl CredentialStore {
+    /// A store without a file; every lookup fails with `NotConfigured`.
+    pub fn disabled() -> Self {
+        Self { inner: None }
+    }
+
+    /// Open the store configured in `[credentials]`, or a disabled one if no
+    /// path is set. A missing file is an empty store.
+    pub fn open(config: &CredentialsConfig) -> Result<Self> {
+        let Some(path) = &config.path else {
+            return Ok(Self::disabled());
+        };
+        let key = master_key(config)?;
+        Self::open_with_key(path, key)
+    }
+
+    fn open_with_key(path: &Path, key: LessSafeKey) -> Result<Self> {
+        let entries = match std::fs::read(path) {
+            Ok(bytes) => {
+                let file: StoreFile = serde_json::from_slice(&bytes)
+                    .with_context(|| format!("Failed to parse credential store {}", path.display()))?;
+                if file.version != STORE_VERSION {
+                    return Err(anyhow!(
+                        "Unsupported credential store version {} in {}",
+                        file.version,
+                        path.display()
+                    ));
+                }
+                file.credentials
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(anyhow!("Failed to read credential store {}: {}", path.display(), e)),
+        };
+
+        let inner = Inner {
+            path: path.to_path_buf(),
+            key,
+            entries: RwLock::new(entries),
+            writing: Mutex::new(()),
+        };
+        // Catch a wrong master key at startup rather than on first use
+        let names: Vec<String> = inner.entries().keys().cloned().collect();
+        for name in &names {
+            inner
+                .open(name)
+                .map_err(|_| anyhow!("Failed to decrypt credential \"{}\"; wrong master key?", name))?;
+        }
+        Ok(Self {
+            inner: Some(Arc::new(inner)),
+        })
+    }
+
+    /// A store at `path` under a fixed master key.
+    #[cfg(test)]
+    pub fn with_test_key(path: &Path) -> Self {
+        Self::open_with_key(path, key_from_bytes(&[7; MASTER_KEY_LEN]).unwrap()).unwrap()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.inner.is_some()
+    }
+
+    pub fn get(&self, name: &str) -> Result<Login, CredentialError> {
+        self.inner.as_ref().ok_or(CredentialError::NotConfigured)?.open(name)
+    }
+
+    /// Stored credential names; logins never leave the store this way.
+    pub fn names(&self) -> Vec<String> {
+        match &self.inner {
+            Some(inner) => inner.entries().keys().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// The login for an NVR: its stored `credential`, or the username and
+    /// password from its config entry.
+    pub fn nvr_login(&self, nvr: &NvrConfig) -> Result<Login, CredentialError> {
+        match &nvr.credential {
+            Some(name) => self.get(name),
+            None => Ok(Login {
+                username: nvr.username.clone(),
+                password: nvr.password.clone(),
+            }),
+        }
+    }
+
+    /// Encrypt a login kept outside the store, such as in the stream
+    /// registry. `context` is bound to it and must match to open it again.
+    pub fn seal(&self, context: &str, login: &Login) -> Result<String> {
+        self.inner.as_ref().ok_or(CredentialError::NotConfigured)?.seal(context, login)
+    }
+
+    /// Decrypt a login from [`CredentialStore::seal`].
+    pub fn unseal(&self, context: &str, sealed: &str) -> Result<Login, CredentialError> {
+        self.inner.as_ref().ok_or(CredentialError::NotConfigured)?.unseal(context, sealed)
+    }
+
+    /// Add or replace a credential and write the store to disk.
+    pub async fn insert(&self, name: &str, login: &Login) -> Result<()> {
+        let inner = self.inner.as_ref().ok_or(CredentialError::NotConfigured)?;
+        let _writing = inner.writing.lock().await;
+        let mut entries = inner.entries().clone();
+        entries.insert(name.to_string(), inner.seal(name, login)?);
+        inner.save(&entries).await?;
+        *inner.entries.write().unwrap_or_else(|e| e.into_inner()) = entries;
+        Ok(())
+    }
+
+    /// Remove a credential; `false` if there was none by that name.
+    pub async fn remove(&self, name: &str) -> Result<bool> {
+        let inner = self.inner.as_ref().ok_or(CredentialError::NotConfigured)?;
+        let _writing = inner.writing.lock().await;
+        let mut entries = inner.entries().clone();
+        if entries.remove(name).is_none() {
+            return Ok(false);
+        }
+        inner.save(&entries).await?;
+        *inner.entries.write().unwrap_or_else(|e| e.into_inner()) = entries;
+        Ok(true)
+    }
+}
+
+impl Inner {
+    fn entries(&self) -> std::sync::RwLockReadGuard<'_, BTreeMap<String, String>> {
+        self.entries.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn seal(&self, name: &str, login: &Login) -> Result<String> {
+        let mut nonce = [0u8; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| anyhow!("Failed to generate a nonce"))?;
+        let mut data = serde_json::to_vec(login)?;
+        self.key
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(name.as_bytes()), &mut data)
+            .map_err(|_| anyhow!("Failed to encrypt credential \"{}\"", name))?;
+        let mut sealed = nonce.to_vec();
+        sealed.extend(data);
+        Ok(STANDARD.encode(sealed))
+    }
+
+    fn open(&self, name: &str) -> Result<Login, CredentialError> {
+        let sealed = self
+            .entries()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| CredentialError::Unknown(name.to_string()))?;
+        self.unseal(name, &sealed)
+    }
+
+    fn unseal(&self, name: &str, sealed: &str) -> Result<Login, CredentialError> {
+        let corrupt = || CredentialError::Corrupt(name.to_string());
+        let bytes = STANDARD.decode(sealed).map_err(|_| corrupt())?;
+        if bytes.len() < NONCE_LEN {
+            return Err(corrupt());
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| corrupt())?;
+        let mut data = ciphertext.to_vec();
+        let plain = self
+            .key
+            .open_in_place(nonce, Aad::from(name.as_bytes()), &mut data)
+            .map_err(|_| corrupt())?;
+        serde_json::from_slice(plain).map_err(|_| corrupt())
+    }
+
+    async fn save(&self, entries: &BTreeMap<String, String>) -> Result<()> {
+        let file = StoreFile {
+            version: STORE_VERSION,
+            credentials: entries.clone(),
+        };
+        let json = serde_json::to_vec_pretty(&file)?;
+        atomic_file::write(&self.path, &json).await
+    }
+}
+
+/// Read the base64 master key from the configured environment variable, or
+/// failing that from `master_key_file`.
+fn master_key(config: &CredentialsConfig) -> Result<LessSafeKey> {
+    let encoded = match (std::env::var(&config.master_key_env), &config.master_key_file) {
+        (Ok(value), _) => value,
+        (Err(_), Some(path)) => std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read master key file {}", path.display()))?,
+        (Err(_), None) => {
+            return Err(anyhow!(
+                "credentials.path is set but ${} is unset and credentials.master_key_file is not configured",
+                config.master_key_env
+            ))
+        }
+    };
+    let bytes = STANDARD
+        .decode(encoded.trim())
+        .map_err(|e| anyhow!("Master key is not valid base64: {}", e))?;
+    key_from_bytes(&bytes)
+}
+
+fn key_from_bytes(bytes: &[u8]) -> Result<LessSafeKey> {
+    if bytes.len() != MASTER_KEY_LEN {
+        return Err(anyhow!(
+            "Master key must be {} bytes, base64-encoded; got {}",
+            MASTER_KEY_LEN,
+            bytes.len()
+        ));
+    }
+    let key = UnboundKey::new(&AES_256_GCM, bytes).map_err(|_| anyhow!("Invalid master key"))?;
+    Ok(LessSafeKey::new(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("rtsp-proxy-credentials-{}.json", uuid::Uuid::new_v4()))
+    }
+
+    fn login(password: &str) -> Login {
+        Login {
+            username: "admin".to_string(),
+            password: password.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn stored_credentials_are_encrypted_and_load_back() {
+        let path = temp_path();
+        let store = CredentialStore::open_with_key(&path, key_from_bytes(&[7; 32]).unwrap()).unwrap();
+        store.insert("lobby", &login("s3cret")).await.unwrap();
+        store.insert("yard", &login("hunter2")).await.unwrap();
+        assert!(store.remove("yard").await.unwrap());
+        assert!(!store.remove("yard").await.unwrap());
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(!text.contains("s3cret") && !text.contains("admin"), "{}", text);
+
+        let loaded = CredentialStore::open_with_key(&path, key_from_bytes(&[7; 32]).unwrap()).unwrap();
+        assert_eq!(loaded.names(), ["lobby"]);
+        assert_eq!(loaded.get("lobby").unwrap(), login("s3cret"));
+        assert!(matches!(loaded.get("yard"), Err(CredentialError::Unknown(_))));
+
+        // The wrong master key is caught when opening the store
+        assert!(CredentialStore::open_with_key(&path, key_from_bytes(&[8; 32]).unwrap()).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn entries_are_bound_to_their_names() {
+        let path = temp_path();
+        let store = CredentialStore::open_with_key(&path, key_from_bytes(&[7; 32]).unwrap()).unwrap();
+        let inner = store.inner.as_ref().unwrap();
+        let sealed = inner.seal("lobby", &login("s3cret")).unwrap();
+        inner.entries.write().unwrap().insert("yard".to_string(), sealed);
+        assert!(matches!(store.get("yard"), Err(CredentialError::Corrupt(_))));
+        assert!(matches!(CredentialStore::disabled().get("yard"), Err(CredentialError::NotConfigured)));
+
+        let sealed = store.seal("stream:lobby", &login("s3cret")).unwrap();
+        assert_eq!(store.unseal("stream:lobby", &sealed).unwrap(), login("s3cret"));
+        assert!(matches!(store.unseal("stream:yard", &sealed), Err(CredentialError::Corrupt(_))));
+    }
+}
@@ -0,0 +1,249 @@
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::process::Command;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::capabilities::Capabilities;
+use crate::config::{Config, SharedConfig};
+use crate::renditions;
+use crate::shutdown::Shutdown;
+
+/// One readiness check and what it found.
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub ok: bool,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &'static str, result: Result<String, String>) -> Self {
+        let (ok, detail) = match result {
+            Ok(detail) => (true, detail),
+            Err(detail) => (false, detail),
+        };
+        Self { name, ok, detail }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: Vec<Check>,
+}
+
+/// The last ffmpeg probe and when it ran.
+type FfmpegCheck = Option<(Instant, Result<Capabilities, String>)>;
+
+/// Runs the `/readyz` checks. The ffmpeg check spawns processes, so its
+/// result is reused for `health.ffmpeg_check_interval_secs`.
+#[derive(Clone)]
+pub struct Health {
+    config: SharedConfig,
+    shutdown: Shutdown,
+    started: Instant,
+    ffmpeg: Arc<Mutex<FfmpegCheck>>,
+}
+
+impl Health {
+    pub fn new(config: SharedConfig, shutdown: Shutdown) -> Self {
+        Self {
+            config,
+            shutdown,
+            started: Instant::now(),
+            ffmpeg: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Check that the server can serve streams: ffmpeg runs and has the
+    /// encoders the profiles need, the HLS temp directory is writable and
+    /// has space, and the server isn't shutting down.
+    pub async fn check(&self) -> Readiness {
+        let config = self.config.current();
+        let ffmpeg = self.ffmpeg_info(&config).await;
+        let checks = vec![
+            Check::new(
+                "ffmpeg",
+                ffmpeg.as_ref().map(|ffmpeg| ffmpeg.version.clone()).map_err(Clone::clone),
+            ),
+            Check::new("encoders", check_encoders(&config, &ffmpeg)),
+            Check::new("temp_dir", check_writable(&config.hls.temp_dir).await),
+            Check::new(
+                "disk_space",
+                check_free_space(&config.hls.temp_dir, config.health.min_free_disk_mb).await,
+            ),
+            Check::new(
+                "accepting",
+                if self.shutdown.is_draining() {
+                    Err("Shutting down".to_string())
+                } else {
+                    Ok("Accepting new streams".to_string())
+                },
+            ),
+        ];
+        Readiness {
+            ready: checks.iter().all(|check| check.ok),
+            checks,
+        }
+    }
+
+    async fn ffmpeg_info(&self, config: &Config) -> Result<Capabilities, String> {
+        // Held across the check so concurrent probes share one run
+        let mut cached = self.ffmpeg.lock().await;
+        let interval = Duration::from_secs(config.health.ffmpeg_check_interval_secs);
+        if let Some((checked, info)) = cached.as_ref() {
+            if checked.elapsed() < interval {
+                return info.clone();
+            }
+        }
+        let info = Capabilities::detect(&config.ffmpeg.path).await;
+        *cached = Some((Instant::now(), info.clone()));
+        info
+    }
+}
+
+/// Encoders the usable profiles and rendition ladders ask ffmpeg for, after
+/// fallbacks were picked at startup or reload.
+fn required_encoders(config: &Config) -> BTreeSet<&str> {
+    let mut required = BTreeSet::new();
+    if let Ok(encoder) = &config.encoders.ladder {
+        required.extend([encoder.as_str(), renditions::LADDER_AUDIO_ENCODER]);
+    }
+    for (name, profile) in &config.profiles {
+        if !config.encoders.unavailable.contains_key(name) {
+            required.extend([profile.video_codec.as_str(), profile.audio_codec.as_str()]);
+        }
+    }
+    required.remove("copy");
+    required
+}
+
+/// Fails when ffmpeg lost an encoder since it was probed, or the default
+/// profile can't be used; other unusable profiles only show in the detail.
+fn check_encoders(config: &Config, ffmpeg: &Result<Capabilities, String>) -> Result<String, String> {
+    let required = required_encoders(config);
+    let Ok(ffmpeg) = ffmpeg else {
+        return Err("ffmpeg is unavailable".to_string());
+    };
+    let missing: Vec<&str> = required
+        .iter()
+        .copied()
+        .filter(|encoder| !ffmpeg.has_encoder(encoder))
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!("Missing encoders: {}", missing.join(", ")));
+    }
+    let default = &config.server.default_profile;
+    if let Some(reason) = config.encoders.unavailable.get(default) {
+        return Err(format!("Default profile \"{}\" can't be used: {}", default, reason));
+    }
+    let mut detail = required.into_iter().collect::<Vec<_>>().join(", ");
+    if !config.encoders.unavailable.is_empty() {
+        let unusable: Vec<&str> = config.encoders.unavailable.keys().map(String::as_str).collect();
+        detail.push_str(&format!("; unusable profiles: {}", unusable.join(", ")));
+    }
+    Ok(detail)
+}
+
+/// Create and remove a file where HLS sessions write their segments.
+async fn check_writable(dir: &Path) -> Result<String, String> {
+    let probe = dir.join(format!(".rtsp-proxy-health-{}", Uuid::new_v4()));
+    let result = async {
+        tokio::fs::create_dir_all(dir).await?;
+        tokio::fs::write(&probe, b"ok").await?;
+        tokio::fs::remove_file(&probe).await
+    }
+    .await;
+    match result {
+        Ok(()) => Ok(format!("{} is writable", dir.display())),
+        Err(e) => Err(format!("{} is not writable: {}", dir.display(), e)),
+    }
+}
+
+/// Passes when free space can't be measured; only a known shortage fails.
+async fn check_free_space(dir: &Path, min_free_mb: u64) -> Result<String, String> {
+    match free_space_mb(dir).await {
+        Some(free) if free < min_free_mb => Err(format!(
+            "{} MB free in {}, below {} MB",
+            free,
+            dir.display(),
+            min_free_mb
+        )),
+        Some(free) => Ok(format!("{} MB free in {}", free, dir.display())),
+        None => Ok(format!("Free space in {} is unknown", dir.display())),
+    }
+}
+
+#[cfg(unix)]
+async fn free_space_mb(dir: &Path) -> Option<u64> {
+    let output = Command::new("df").arg("-Pk").arg(dir).output().await.ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    parse_df(&String::from_utf8_lossy(&output.stdout)).map(|kb| kb / 1024)
+}
+
+#[cfg(not(unix))]
+async fn free_space_mb(_dir: &Path) -> Option<u64> {
+    None
+}
+
+/// Available KiB from POSIX `df -Pk` output: the fourth column of the
+/// second line.
+#[cfg(unix)]
+fn parse_df(output: &str) -> Option<u64> {
+    output.lines().nth(1)?.split_whitespace().nth(3)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_df_output() {
+        assert_eq!(
+            parse_df("Filesystem 1024-blocks Used Available Capacity Mounted on\n/dev/sda1 102400 51200 51200 50% /\n"),
+            Some(51200)
+        );
+        assert_eq!(parse_df("df: /missing: No such file or directory\n"), None);
+    }
+
+    #[test]
+    fn encoders_come_from_usable_profiles_and_ladders() {
+        let mut config = Config::default();
+        config.profiles.get_mut("default").unwrap().video_codec = "copy".to_string();
+        config.profiles.insert(
+            "hevc".to_string(),
+            crate::config::Profile {
+                video_codec: "libx265".to_string(),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            required_encoders(&config).into_iter().collect::<Vec<_>>(),
+            ["aac", "libx264", "libx265"]
+        );
+        let ffmpeg = Ok(Capabilities::with_encoders(&["libx264", "aac"]));
+        assert_eq!(check_encoders(&config, &ffmpeg), Err("Missing encoders: libx265".to_string()));
+
+        // As left by Capabilities::apply for an ffmpeg without libx265
+        config
+            .encoders
+            .unavailable
+            .insert("hevc".to_string(), "ffmpeg has no libx265 encoder".to_string());
+        assert_eq!(
+            check_encoders(&config, &ffmpeg),
+            Ok("aac, libx264; unusable profiles: hevc".to_string())
+        );
+        config.server.default_profile = "hevc".to_string();
+        assert!(check_encoders(&config, &ffmpeg).is_err());
+    }
+}
@@ -0,0 +1,994 @@
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use thiserror::Error;
+use notify::{RecursiveMode, Watcher};
+use tokio::process::Command;
+use tokio::sync::{mpsc, watch, RwLock};
+use tracing::{error, info, info_span, warn, Instrument};
+use uuid::Uuid;
+
+use crate::admission::{Admission, Claim, Permit, Rejected};
+use crate::config::{Config, Profile, SharedConfig};
+use crate::janitor::{self, CleanupReport, ProcessTracker};
+use crate::metrics::{FfmpegKind, Metrics};
+use crate::probe::{self, SourceInfo};
+use crate::redact::{Redacted, SourceUrl};
+use crate::renditions::{self, Rendition};
+
+/// How long a new session may take to write its first playlist.
+const READY_TIMEOUT: Duration = Duration::from_secs(20);
+/// Upper bound on how often the reaper looks for idle sessions.
+const REAP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The URL family a session is served under.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionKind {
+    /// `/stream/hls?rtsp_url=...`, served from `/stream/hls/{id}/`
+    Stream,
+    /// `/proxyhl/rtsp?ip=...`, served from `/proxyhl/segment/{id}/`
+    Proxyhl,
+}
+
+impl SessionKind {
+    fn dir_tag(self) -> &'static str {
+        match self {
+            SessionKind::Stream => "stream",
+            SessionKind::Proxyhl => "proxyhl",
+        }
+    }
+
+    /// URL prefix that playlists and segments of a session are served from.
+    pub fn base_url(self, id: &str) -> String {
+        match self {
+            SessionKind::Stream => format!("/stream/hls/{}/", id),
+            SessionKind::Proxyhl => format!("/proxyhl/segment/{}/", id),
+        }
+    }
+
+    pub fn playlist_url(self, id: &str) -> String {
+        format!("{}{}", self.base_url(id), renditions::PLAYLIST_NAME)
+    }
+
+    fn unavailable_message(self) -> &'static str {
+        match self {
+            SessionKind::Stream => "HLS playlist not available; source may be unreachable",
+            SessionKind::Proxyhl => {
+                "HLS playlist not available; source may be unreachable or credentials invalid"
+            }
+        }
+    }
+}
+
+/// Where a session is in its lifecycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionStatus {
+    /// ffmpeg hasn't written the first playlist yet
+    Starting,
+    /// Players can load the playlist
+    Ready,
+    /// The session ended before it became ready
+    Failed,
+    /// The session ended after becoming ready
+    Stopped,
+}
+
+impl SessionStatus {
+    pub fn is_finished(self) -> bool {
+        matches!(self, SessionStatus::Failed | SessionStatus::Stopped)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum HlsSessionError {
+    /// Unknown profile or malformed rendition ladder
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("{0}")]
+    Internal(String),
+    /// ffmpeg never produced a playlist
+    #[error("{0}")]
+    Unavailable(&'static str),
+    /// Admission control turned the session away
+    #[error(transparent)]
+    Rejected(#[from] Rejected),
+    #[error("No HLS session {0}")]
+    NotFound(String),
+    /// The session can't take the requested action in its current state
+    #[error("{0}")]
+    Conflict(String),
+}
+
+/// Parameters for a new session.
+pub struct SessionRequest<'a> {
+    pub kind: SessionKind,
+    pub rtsp_url: String,
+    /// Rendition ladder, e.g. `source,720p,360p`; defaults to the profile's
+    pub renditions: Option<&'a str>,
+    pub profile: Option<&'a str>,
+}
+
+struct HlsSession {
+    kind: SessionKind,
+    /// Identifies the output; requests with the same key share the session
+    key: String,
+    tmp_dir: String,
+    rtsp_url: String,
+    renditions: Vec<String>,
+    /// Published by the session task; waiters subscribe instead of polling
+    status: watch::Sender<SessionStatus>,
+    last_access: Instant,
+    /// Viewers by client address, with the time of their last request
+    viewers: HashMap<IpAddr, Instant>,
+    /// pid and start time of the running ffmpeg; `None` while it isn't running
+    ffmpeg: Option<(u32, SystemTime)>,
+    shutdown: mpsc::Sender<()>,
+    /// Set once shutdown was requested; the session takes no new viewers
+    stopping: bool,
+    restart: mpsc::Sender<()>,
+    /// Admission slot, released when the session is removed
+    _permit: Permit,
+}
+
+impl HlsSession {
+    fn touch(&mut self, viewer: IpAddr) {
+        let now = Instant::now();
+        self.last_access = now;
+        if self.viewers.insert(viewer, now).is_none() {
+            info!("Viewer {} joined HLS session ({} watching)", viewer, self.viewers.len());
+        }
+    }
+
+    /// Signal the session task to stop; `false` if it already was.
+    fn request_shutdown(&mut self) -> bool {
+        let first = !self.stopping;
+        self.stopping = true;
+        let _ = self.shutdown.try_send(());
+        first
+    }
+}
+
+/// Snapshot of a session for listings.
+#[derive(Debug, Serialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub kind: SessionKind,
+    pub rtsp_url: SourceUrl,
+    pub renditions: Vec<String>,
+    pub status: SessionStatus,
+    pub viewers: usize,
+    pub last_access_secs: u64,
+}
+
+impl SessionInfo {
+    fn new(id: &str, session: &HlsSession) -> Self {
+        Self {
+            id: id.to_string(),
+            kind: session.kind,
+            rtsp_url: SourceUrl::new(session.rtsp_url.clone()),
+            renditions: session.renditions.clone(),
+            status: *session.status.borrow(),
+            viewers: session.viewers.len(),
+            last_access_secs: session.last_access.elapsed().as_secs(),
+        }
+    }
+}
+
+/// Detailed status of a single session.
+#[derive(Debug, Serialize)]
+pub struct SessionDetail {
+    #[serde(flatten)]
+    pub info: SessionInfo,
+    pub playlist_url: String,
+    /// pid of the session's ffmpeg; absent while it is starting or restarting
+    pub ffmpeg_pid: Option<u32>,
+    /// Unix time the current ffmpeg was started
+    pub started_at: Option<u64>,
+    pub segment_count: usize,
+    /// Contents of the session's playlist, once ffmpeg has written it
+    pub playlist: Option<String>,
+}
+
+/// Owns every on-demand HLS session: the ffmpeg process writing it, its
+/// directory under `hls.temp_dir` and its idle timer.
+///
+/// Requests for the same source, profile and ladder share one session and
+/// one ffmpeg. Each client address that fetches a playlist or segment counts
+/// as a viewer until it has been silent for `hls.idle_timeout_secs`; a single
+/// reaper task stops the session once its last viewer is gone.
+#[derive(Clone)]
+pub struct HlsSessionManager {
+    config: SharedConfig,
+    processes: ProcessTracker,
+    admission: Admission,
+    metrics: Metrics,
+    sessions: Arc<RwLock<HashMap<String, HlsSession>>>,
+}
+
+impl HlsSessionManager {
+    pub fn new(config: SharedConfig, processes: ProcessTracker, admission: Admission, metrics: Metrics) -> Self {
+        Self {
+            config,
+            processes,
+            admission,
+            metrics,
+            sessions: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Join the session for this source, profile and ladder, starting one if
+    /// none exists, and wait until its first playlist is written.
+    ///
+    /// Returns the session id; the playlist is at `kind.playlist_url(&id)`.
+    pub async fn create(&self, request: SessionRequest<'_>, viewer: IpAddr) -> Result<String, HlsSessionError> {
+        let kind = request.kind;
+        let id = self.start(request, viewer).await?;
+        self.wait_ready(kind, &id).await?;
+        Ok(id)
+    }
+
+    /// Like `create`, but returns as soon as the session exists. Follow its
+    /// progress with `subscribe`.
+    ///
+    /// Joining a running session is always allowed; starting one needs an
+    /// admission slot, which may mean queueing for it.
+    pub async fn start(&self, request: SessionRequest<'_>, viewer: IpAddr) -> Result<String, HlsSessionError> {
+        let config = self.config.current();
+        let profile_name = request.profile.unwrap_or(&config.server.default_profile);
+        let profile = config
+            .profile(Some(profile_name))
+            .map_err(|e| HlsSessionError::BadRequest(e.to_string()))?
+            .clone();
+        let default_spec = profile.renditions.join(",");
+        let ladder = renditions::parse_ladder(request.renditions.unwrap_or(&default_spec))
+            .map_err(|e| HlsSessionError::BadRequest(e.to_string()))?;
+        if ladder.iter().any(|r| !r.is_passthrough()) {
+            if let Err(reason) = &config.encoders.ladder {
+                return Err(HlsSessionError::BadRequest(format!(
+                    "Transcoded renditions are unavailable: {}",
+                    reason
+                )));
+            }
+        }
+
+        let kind = request.kind;
+        let key = session_key(kind, &request.rtsp_url, profile_name, &ladder);
+        if let Some(id) = join(&mut *self.sessions.write().await, &key, viewer) {
+            return Ok(id);
+        }
+
+        // Queue for a slot without holding up the other sessions
+        let permit = self
+            .admission
+            .acquire(&Claim::new(&request.rtsp_url, Some(viewer)))
+            .await?;
+        let mut sessions = self.sessions.write().await;
+        // Another request may have started the session while this one queued
+        let id = match join(&mut sessions, &key, viewer) {
+            Some(id) => id,
+            None => {
+                let id = Uuid::new_v4().to_string();
+                let tmp_dir = config.hls_session_dir(kind.dir_tag(), &id);
+                if let Err(e) = std::fs::create_dir_all(&tmp_dir) {
+                    error!("Failed to create temp directory: {}", e);
+                    return Err(HlsSessionError::Internal(format!(
+                        "Failed to create temp directory: {}",
+                        e
+                    )));
+                }
+
+                let (shutdown_tx, shutdown_rx) = mpsc::channel::<()>(1);
+                let (restart_tx, restart_rx) = mpsc::channel::<()>(1);
+                let mut session = HlsSession {
+                    kind,
+                    key,
+                    tmp_dir: tmp_dir.clone(),
+                    rtsp_url: request.rtsp_url.clone(),
+                    renditions: ladder.iter().map(|r| r.name.clone()).collect(),
+                    status: watch::Sender::new(SessionStatus::Starting),
+                    last_access: Instant::now(),
+                    viewers: HashMap::new(),
+                    ffmpeg: None,
+                    shutdown: shutdown_tx,
+                    stopping: false,
+                    restart: restart_tx,
+                    _permit: permit,
+                };
+                session.touch(viewer);
+                sessions.insert(id.clone(), session);
+
+                let setup = SessionSetup {
+                    id: id.clone(),
+                    kind,
+                    tmp_dir,
+                    rtsp_url: request.rtsp_url,
+                    ladder,
+                    config: config.clone(),
+                    profile,
+                };
+                // The session outlives the request; its logs carry its id instead
+                let span = info_span!(parent: None, "hls_session", session = %id);
+                tokio::spawn(self.clone().run_session(setup, shutdown_rx, restart_rx).instrument(span));
+                id
+            }
+        };
+        Ok(id)
+    }
+
+    /// Wait until the session is ready. The session task gives up on
+    /// sessions that write no playlist within `READY_TIMEOUT`, so this
+    /// doesn't need a timeout of its own.
+    async fn wait_ready(&self, kind: SessionKind, id: &str) -> Result<(), HlsSessionError> {
+        let unavailable = || HlsSessionError::Unavailable(kind.unavailable_message());
+        // A missing session means ffmpeg already failed
+        let (_, mut status) = self.subscribe(id).await.ok_or_else(unavailable)?;
+        let ready = status
+            .wait_for(|status| *status != SessionStatus::Starting)
+            .await
+            .is_ok_and(|status| *status == SessionStatus::Ready);
+        if ready {
+            Ok(())
+        } else {
+            Err(unavailable())
+        }
+    }
+
+    /// The session's kind and a receiver for its status changes; the channel
+    /// closes once the session is gone.
+    pub async fn subscribe(&self, id: &str) -> Option<(SessionKind, watch::Receiver<SessionStatus>)> {
+        let sessions = self.sessions.read().await;
+        let session = sessions.get(id)?;
+        Some((session.kind, session.status.subscribe()))
+    }
+
+    /// The RTSP URL a session reads, or `None` if it doesn't exist.
+    pub async fn source(&self, id: &str) -> Option<String> {
+        self.sessions.read().await.get(id).map(|session| session.rtsp_url.clone())
+    }
+
+    /// Probe the source, write the master playlist and run the session's
+    /// ffmpeg until it exits or the session is stopped, then remove the
+    /// session and its directory. A restart request replaces the ffmpeg and
+    /// keeps the session.
+    async fn run_session(
+        self,
+        setup: SessionSetup,
+        mut shutdown_rx: mpsc::Receiver<()>,
+        mut restart_rx: mpsc::Receiver<()>,
+    ) {
+        let SessionSetup { id, kind, tmp_dir, rtsp_url, ladder, config, profile } = setup;
+        let started = Instant::now();
+
+        let prepared = tokio::select! {
+            prepared = prepare_ladder(&rtsp_url, &tmp_dir, ladder, &config) => prepared,
+            _ = shutdown_rx.recv() => None,
+        };
+        if let Some((ladder, source)) = prepared {
+            if let Some(session) = self.sessions.write().await.get_mut(&id) {
+                session.renditions = ladder.iter().map(|r| r.name.clone()).collect();
+            }
+            let ready_file = format!("{}/{}", tmp_dir, renditions::readiness_file(&ladder));
+            let mut readiness = Readiness {
+                file: &ready_file,
+                started,
+            };
+            let args = renditions::hls_ffmpeg_args(
+                &rtsp_url,
+                &tmp_dir,
+                &kind.base_url(&id),
+                &ladder,
+                &source,
+                &config,
+                &profile,
+            );
+            while let FfmpegExit::Restart =
+                self.run_ffmpeg(&id, &config.ffmpeg.path, &args, &readiness, &mut shutdown_rx, &mut restart_rx)
+                    .await
+            {
+                info!("Restarting ffmpeg for HLS session {}", id);
+                self.metrics.ffmpeg_restarted(FfmpegKind::Hls);
+                readiness.restarted();
+            }
+        }
+
+        let _ = tokio::fs::remove_dir_all(&tmp_dir).await;
+        if let Some(session) = self.sessions.write().await.remove(&id) {
+            session.status.send_modify(|status| {
+                *status = match status {
+                    SessionStatus::Starting => SessionStatus::Failed,
+                    _ => SessionStatus::Stopped,
+                }
+            });
+        }
+    }
+
+    async fn run_ffmpeg(
+        &self,
+        id: &str,
+        ffmpeg: &Path,
+        args: &[String],
+        readiness: &Readiness<'_>,
+        shutdown_rx: &mut mpsc::Receiver<()>,
+        restart_rx: &mut mpsc::Receiver<()>,
+    ) -> FfmpegExit {
+        let mut child = match Command::new(ffmpeg)
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) => {
+                error!("Failed to start FFmpeg for HLS: {}", e);
+                self.metrics
+                    .ffmpeg_failed(FfmpegKind::Hls, format!("HLS session {}: failed to start ffmpeg: {}", id, e));
+                return FfmpegExit::Finished;
+            }
+        };
+        self.metrics.ffmpeg_spawned(FfmpegKind::Hls);
+        let _pid = self.processes.track(&child);
+        self.set_ffmpeg(id, child.id().map(|pid| (pid, SystemTime::now()))).await;
+        // A restarted session stays ready; the old playlist keeps being served
+        let mut ready = self
+            .subscribe(id)
+            .await
+            .is_some_and(|(_, status)| *status.borrow() == SessionStatus::Ready);
+        let exit = loop {
+            tokio::select! {
+                _ = shutdown_rx.recv() => {
+                    info!("Shutting down HLS session {} due to inactivity or explicit stop", id);
+                    let _ = child.kill().await;
+                    break FfmpegExit::Finished;
+                }
+                _ = restart_rx.recv() => {
+                    let _ = child.kill().await;
+                    break FfmpegExit::Restart;
+                }
+                status = child.wait() => {
+                    info!("HLS ffmpeg process exited for session {}", id);
+                    match status {
+                        Ok(status) if status.success() => {}
+                        Ok(status) => self.metrics.ffmpeg_failed(
+                            FfmpegKind::Hls,
+                            format!("HLS session {}: ffmpeg exited with {}", id, status),
+                        ),
+                        Err(e) => self.metrics.ffmpeg_failed(
+                            FfmpegKind::Hls,
+                            format!("HLS session {}: failed to wait for ffmpeg: {}", id, e),
+                        ),
+                    }
+                    break FfmpegExit::Finished;
+                }
+                _ = wait_for_file(readiness.file), if !ready => {
+                    info!("HLS session {} is ready", id);
+                    ready = true;
+                    self.metrics.hls_session_ready(readiness.started.elapsed());
+                    if let Some(session) = self.sessions.read().await.get(id) {
+                        session.status.send_replace(SessionStatus::Ready);
+                    }
+                }
+                _ = tokio::time::sleep_until(readiness.deadline().into()), if !ready => {
+                    error!("HLS session {} produced no playlist after {:?}", id, READY_TIMEOUT);
+                    self.metrics.ffmpeg_failed(
+                        FfmpegKind::Hls,
+                        format!("HLS session {}: no playlist after {:?}", id, READY_TIMEOUT),
+                    );
+                    let _ = child.kill().await;
+                    break FfmpegExit::Finished;
+                }
+            }
+        };
+        self.set_ffmpeg(id, None).await;
+        exit
+    }
+
+    async fn set_ffmpeg(&self, id: &str, ffmpeg: Option<(u32, SystemTime)>) {
+        if let Some(session) = self.sessions.write().await.get_mut(id) {
+            session.ffmpeg = ffmpeg;
+        }
+    }
+
+    /// Record viewer activity and return the session directory, or `None` if
+    /// no such session of this kind exists.
+    pub async fn touch(&self, kind: SessionKind, id: &str, viewer: IpAddr) -> Option<PathBuf> {
+        let mut sessions = self.sessions.write().await;
+        let session = sessions.get_mut(id).filter(|s| s.kind == kind)?;
+        session.touch(viewer);
+        Some(PathBuf::from(&session.tmp_dir))
+    }
+
+    /// Ask a session's ffmpeg to stop; the session disappears once it has.
+    pub async fn stop(&self, id: &str) -> bool {
+        match self.sessions.write().await.get_mut(id) {
+            Some(session) => {
+                session.request_shutdown();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Kill a session's ffmpeg and start a new one under the same id, keeping
+    /// its directory and viewers.
+    pub async fn restart(&self, id: &str) -> Result<(), HlsSessionError> {
+        let sessions = self.sessions.read().await;
+        let session = sessions
+            .get(id)
+            .ok_or_else(|| HlsSessionError::NotFound(id.to_string()))?;
+        if session.stopping {
+            return Err(HlsSessionError::Conflict(format!("HLS session {} is stopping", id)));
+        }
+        if session.ffmpeg.is_none() {
+            return Err(HlsSessionError::Conflict(format!(
+                "HLS session {} has no running ffmpeg to restart",
+                id
+            )));
+        }
+        // A restart already pending covers this one too
+        let _ = session.restart.try_send(());
+        Ok(())
+    }
+
+    /// Detailed status of one session, or `None` if it doesn't exist.
+    pub async fn get(&self, id: &str) -> Option<SessionDetail> {
+        let (info, ffmpeg, tmp_dir) = {
+            let sessions = self.sessions.read().await;
+            let session = sessions.get(id)?;
+            (SessionInfo::new(id, session), session.ffmpeg, session.tmp_dir.clone())
+        };
+        let playlist_path = Path::new(&tmp_dir).join(renditions::PLAYLIST_NAME);
+        Some(SessionDetail {
+            playlist_url: info.kind.playlist_url(id),
+            ffmpeg_pid: ffmpeg.map(|(pid, _)| pid),
+            started_at: ffmpeg.and_then(|(_, started)| {
+                started.duration_since(SystemTime::UNIX_EPOCH).ok().map(|d| d.as_secs())
+            }),
+            segment_count: segment_count(Path::new(&tmp_dir)).await,
+            playlist: tokio::fs::read_to_string(playlist_path).await.ok(),
+            info,
+        })
+    }
+
+    /// Sessions, most recently accessed first.
+    pub async fn list(&self) -> Vec<SessionInfo> {
+        let mut sessions: Vec<SessionInfo> = self
+            .sessions
+            .read()
+            .await
+            .iter()
+            .map(|(id, session)| SessionInfo::new(id, session))
+            .collect();
+        sessions.sort_by_key(|s| s.last_access_secs);
+        sessions
+    }
+
+    pub async fn len(&self) -> usize {
+        self.sessions.read().await.len()
+    }
+
+    /// Start the task that drops viewers silent for `hls.idle_timeout_secs`
+    /// and stops sessions that have none left.
+    pub fn spawn_reaper(&self) {
+        let manager = self.clone();
+        tokio::spawn(async move {
+            loop {
+                // Re-read the timeout so a reload applies to running sessions too
+                let idle_timeout = manager.config.current().hls_idle_timeout();
+                tokio::time::sleep(REAP_INTERVAL.min(idle_timeout)).await;
+
+                let mut sessions = manager.sessions.write().await;
+                for (id, session) in sessions.iter_mut() {
+                    session.viewers.retain(|viewer, last_seen| {
+                        let watching = last_seen.elapsed() <= idle_timeout;
+                        if !watching {
+                            info!("Viewer {} left HLS session {}", viewer, id);
+                        }
+                        watching
+                    });
+                    if session.viewers.is_empty() && !session.stopping {
+                        info!("HLS session {} has no viewers left; requesting shutdown", id);
+                        session.request_shutdown();
+                        manager.metrics.hls_idle_reaped();
+                    }
+                }
+            }
+        });
+    }
+
+    /// Start the task that periodically removes session directories no live
+    /// session owns, e.g. left behind when an ffmpeg was killed mid-write.
+    pub fn spawn_janitor(&self) {
+        let manager = self.clone();
+        tokio::spawn(async move {
+            loop {
+                // Re-read the interval so a reload can enable or retune the sweep
+                let interval = manager.config.current().hls.janitor_interval_secs;
+                if interval == 0 {
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                    continue;
+                }
+                tokio::time::sleep(Duration::from_secs(interval)).await;
+                manager.sweep_orphaned_dirs().await.log("HLS janitor");
+            }
+        });
+    }
+
+    pub async fn sweep_orphaned_dirs(&self) -> CleanupReport {
+        let config = self.config.current();
+        let active: HashSet<PathBuf> = self
+            .sessions
+            .read()
+            .await
+            .values()
+            .map(|session| PathBuf::from(&session.tmp_dir))
+            .collect();
+        let temp_dir = config.hls.temp_dir.clone();
+        let prefix = janitor::session_dir_prefix(std::process::id());
+        // Anything touched within the idle timeout is left alone in case an
+        // ffmpeg we don't know about is still writing to it
+        let min_age = config.hls_idle_timeout();
+        let removed_dirs = tokio::task::spawn_blocking(move || {
+            janitor::remove_orphaned_dirs(&temp_dir, &prefix, &active, min_age)
+        })
+        .await
+        .unwrap_or_default();
+        CleanupReport {
+            removed_dirs,
+            killed_pids: Vec::new(),
+        }
+    }
+
+    /// Stop every session, wait for their ffmpeg processes to exit and remove
+    /// any session directory left behind.
+    pub async fn stop_all(&self, timeout: Duration) {
+        let dirs: Vec<String> = {
+            let mut sessions = self.sessions.write().await;
+            sessions
+                .values_mut()
+                .map(|session| {
+                    session.request_shutdown();
+                    session.tmp_dir.clone()
+                })
+                .collect()
+        };
+        if dirs.is_empty() {
+            return;
+        }
+        info!("Stopping {} HLS sessions", dirs.len());
+
+        let deadline = Instant::now() + timeout;
+        while self.len().await > 0 {
+            if Instant::now() >= deadline {
+                warn!("Timed out waiting for HLS sessions to stop");
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        for dir in dirs {
+            if Path::new(&dir).exists() {
+                if let Err(e) = tokio::fs::remove_dir_all(&dir).await {
+                    error!("Failed to remove HLS directory {}: {}", dir, e);
+                }
+            }
+        }
+    }
+}
+
+/// Fit the ladder to the source and, for multi-variant sessions, write the
+/// master playlist into the session directory. `None` if it couldn't be
+/// written.
+async fn prepare_ladder(
+    rtsp_url: &str,
+    tmp_dir: &str,
+    ladder: Vec<Rendition>,
+    config: &Config,
+) -> Option<(Vec<Rendition>, SourceInfo)> {
+    if ladder.is_empty() {
+        return Some((ladder, SourceInfo::default()));
+    }
+
+    let source = probe::probe_source_or_default(&config.ffmpeg, rtsp_url).await;
+    let ladder = renditions::fit_ladder(ladder, &source);
+    let master = renditions::master_playlist(&ladder, &source, config.encoders.ladder_encoder());
+    let master_path = format!("{}/{}", tmp_dir, renditions::PLAYLIST_NAME);
+    if let Err(e) = tokio::fs::write(&master_path, master).await {
+        error!("Failed to write master playlist {}: {}", master_path, e);
+        return None;
+    }
+    info!(
+        "HLS ladder: {}",
+        ladder.iter().map(|r| r.name.as_str()).collect::<Vec<_>>().join(", ")
+    );
+    Some((ladder, source))
+}
+
+/// Add `viewer` to the live session with this key and return its id.
+fn join(sessions: &mut HashMap<String, HlsSession>, key: &str, viewer: IpAddr) -> Option<String> {
+    // A stopping or finished session is only waiting to be removed
+    let (id, session) = sessions
+        .iter_mut()
+        .find(|(_, s)| s.key == key && !s.stopping && !s.status.borrow().is_finished())?;
+    info!("Reusing HLS session {} for {}", id, Redacted(&session.rtsp_url));
+    session.touch(viewer);
+    Some(id.clone())
+}
+
+/// Number of segments currently on disk in a session directory.
+async fn segment_count(dir: &Path) -> usize {
+    let Ok(mut entries) = tokio::fs::read_dir(dir).await else {
+        return 0;
+    };
+    let mut count = 0;
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if entry.path().extension().is_some_and(|ext| ext == "ts") {
+            count += 1;
+        }
+    }
+    count
+}
+
+/// Resolves once `path` exists and is non-empty. Each session runs one of
+/// these, however many requests are waiting on it. ffmpeg's writes to the
+/// session directory wake it; the file is only looked at when they do.
+async fn wait_for_file(path: &str) {
+    let (events, mut changed) = mpsc::unbounded_channel();
+    let watcher = notify::recommended_watcher(move |_: notify::Result<notify::Event>| {
+        let _ = events.send(());
+    })
+    .and_then(|mut watcher| {
+        let dir = Path::new(path).parent().unwrap_or(Path::new("."));
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        Ok(watcher)
+    });
+    let _watcher = match watcher {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            // Only when the platform runs out of watches, e.g. inotify's limit
+            warn!("Can't watch {} ({}); checking for it every second instead", path, e);
+            while !is_written(path).await {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            return;
+        }
+    };
+    // Checked once the watch is in place, so a write in between isn't missed
+    while !is_written(path).await {
+        if changed.recv().await.is_none() {
+            return std::future::pending().await;
+        }
+    }
+}
+
+async fn is_written(path: &str) -> bool {
+    tokio::fs::metadata(path).await.is_ok_and(|meta| meta.len() > 0)
+}
+
+/// The file that marks a session ready, and when the session's current
+/// ffmpeg started; it is given up on `READY_TIMEOUT` later.
+struct Readiness<'a> {
+    file: &'a str,
+    started: Instant,
+}
+
+impl Readiness<'_> {
+    fn deadline(&self) -> Instant {
+        self.started + READY_TIMEOUT
+    }
+
+    /// A new ffmpeg gets the full `READY_TIMEOUT` to write its playlist.
+    fn restarted(&mut self) {
+        self.started = Instant::now();
+    }
+}
+
+/// How a run of a session's ffmpeg ended.
+enum FfmpegExit {
+    /// The process exited or the session was stopped
+    Finished,
+    Restart,
+}
+
+/// Everything `run_session` needs to bring a new session up.
+struct SessionSetup {
+    id: String,
+    kind: SessionKind,
+    tmp_dir: String,
+    rtsp_url: String,
+    ladder: Vec<Rendition>,
+    config: Arc<Config>,
+    profile: Profile,
+}
+
+/// Sessions with equal keys produce identical output, so they can be shared.
+fn session_key(kind: SessionKind, rtsp_url: &str, profile: &str, ladder: &[Rendition]) -> String {
+    let ladder: Vec<&str> = ladder.iter().map(|r| r.name.as_str()).collect();
+    format!(
+        "{}|{}|{}|{}",
+        kind.dir_tag(),
+        normalize_source(rtsp_url),
+        profile,
+        ladder.join(",")
+    )
+}
+
+/// Canonical form of a source URL so that trivially different spellings of
+/// the same camera share a session: lowercase scheme and host, no default
+/// RTSP port, no fragment.
+fn normalize_source(rtsp_url: &str) -> String {
+    let trimmed = rtsp_url.trim();
+    let Ok(mut url) = url::Url::parse(trimmed) else {
+        return trimmed.to_string();
+    };
+    let default_port = match url.scheme() {
+        "rtsp" => Some(554),
+        "rtsps" => Some(322),
+        _ => None,
+    };
+    if url.port().is_some() && url.port() == default_port {
+        let _ = url.set_port(None);
+    }
+    // `url` only lowercases hosts for schemes it knows, which excludes rtsp
+    if let Some(host) = url.host_str().map(|h| h.to_ascii_lowercase()) {
+        let _ = url.set_host(Some(&host));
+    }
+    url.set_fragment(None);
+    url.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn manager() -> (HlsSessionManager, PathBuf) {
+        let pids = std::env::temp_dir().join(format!("rtsp-proxy-pids-{}", Uuid::new_v4()));
+        let config = SharedConfig::new(Arc::new(Config::default()));
+        let manager = HlsSessionManager::new(
+            config.clone(),
+            ProcessTracker::new(pids.clone()).unwrap(),
+            Admission::new(config),
+            Metrics::new(),
+        );
+        (manager, pids)
+    }
+
+    /// Register a session with no ffmpeg behind it.
+    async fn insert(
+        manager: &HlsSessionManager,
+        kind: SessionKind,
+        id: &str,
+    ) -> (mpsc::Receiver<()>, mpsc::Receiver<()>) {
+        let (shutdown, shutdown_rx) = mpsc::channel(1);
+        let (restart, restart_rx) = mpsc::channel(1);
+        manager.sessions.write().await.insert(
+            id.to_string(),
+            HlsSession {
+                kind,
+                key: id.to_string(),
+                tmp_dir: format!("/nonexistent/{}", id),
+                rtsp_url: "rtsp://camera/live".to_string(),
+                renditions: Vec::new(),
+                status: watch::Sender::new(SessionStatus::Starting),
+                last_access: Instant::now(),
+                viewers: HashMap::new(),
+                ffmpeg: None,
+                shutdown,
+                stopping: false,
+                restart,
+                _permit: manager.admission.try_acquire(&Claim::default()).unwrap(),
+            },
+        );
+        (shutdown_rx, restart_rx)
+    }
+
+    #[tokio::test]
+    async fn touch_finds_sessions_of_the_requested_kind_only() {
+        let (manager, pids) = manager();
+        let _rx = insert(&manager, SessionKind::Stream, "a").await;
+        let viewer = IpAddr::from([127, 0, 0, 1]);
+
+        assert_eq!(
+            manager.touch(SessionKind::Stream, "a", viewer).await,
+            Some(PathBuf::from("/nonexistent/a"))
+        );
+        assert_eq!(manager.touch(SessionKind::Proxyhl, "a", viewer).await, None);
+        assert_eq!(manager.touch(SessionKind::Stream, "b", viewer).await, None);
+        let _ = std::fs::remove_file(pids);
+    }
+
+    #[tokio::test]
+    async fn stop_signals_the_session_and_reports_unknown_ids() {
+        let (manager, pids) = manager();
+        let (mut rx, _) = insert(&manager, SessionKind::Proxyhl, "a").await;
+
+        let viewer = IpAddr::from([127, 0, 0, 1]);
+        assert_eq!(join(&mut *manager.sessions.write().await, "a", viewer).as_deref(), Some("a"));
+        assert!(manager.stop("a").await);
+        assert!(rx.try_recv().is_ok());
+        // Still there until ffmpeg exits, but no longer joinable
+        assert!(join(&mut *manager.sessions.write().await, "a", viewer).is_none());
+        assert!(!manager.stop("b").await);
+        let _ = std::fs::remove_file(pids);
+    }
+
+    #[tokio::test]
+    async fn restart_requires_a_running_ffmpeg() {
+        let (manager, pids) = manager();
+        let (_, mut rx) = insert(&manager, SessionKind::Proxyhl, "a").await;
+
+        assert!(matches!(manager.restart("a").await, Err(HlsSessionError::Conflict(_))));
+        assert!(matches!(manager.restart("b").await, Err(HlsSessionError::NotFound(_))));
+
+        manager.set_ffmpeg("a", Some((42, SystemTime::now()))).await;
+        assert!(manager.restart("a").await.is_ok());
+        assert!(rx.try_recv().is_ok());
+
+        let detail = manager.get("a").await.unwrap();
+        assert_eq!(detail.ffmpeg_pid, Some(42));
+        assert_eq!(detail.playlist_url, "/proxyhl/segment/a/playlist.m3u8");
+        assert_eq!(detail.segment_count, 0);
+        assert!(detail.playlist.is_none());
+        let _ = std::fs::remove_file(pids);
+    }
+
+    #[tokio::test]
+    async fn wait_ready_follows_the_session_status() {
+        let (manager, pids) = manager();
+        let _a = insert(&manager, SessionKind::Stream, "a").await;
+        let _b = insert(&manager, SessionKind::Stream, "b").await;
+
+        let waiting = tokio::spawn({
+            let manager = manager.clone();
+            async move { manager.wait_ready(SessionKind::Stream, "a").await }
+        });
+        manager.sessions.read().await["a"].status.send_replace(SessionStatus::Ready);
+        assert!(waiting.await.unwrap().is_ok());
+
+        manager.sessions.write().await.remove("b").unwrap().status.send_replace(SessionStatus::Failed);
+        assert!(matches!(
+            manager.wait_ready(SessionKind::Stream, "b").await,
+            Err(HlsSessionError::Unavailable(_))
+        ));
+        let _ = std::fs::remove_file(pids);
+    }
+
+    #[test]
+    fn restarts_get_a_fresh_ready_timeout() {
+        let mut readiness = Readiness {
+            file: "playlist.m3u8",
+            started: Instant::now() - READY_TIMEOUT,
+        };
+        assert!(readiness.deadline() <= Instant::now());
+        readiness.restarted();
+        assert!(readiness.deadline() > Instant::now() + READY_TIMEOUT / 2);
+    }
+
+    #[tokio::test]
+    async fn wait_for_file_wakes_on_the_write() {
+        let dir = std::env::temp_dir().join(format!("hls-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let playlist = dir.join(renditions::PLAYLIST_NAME).to_string_lossy().into_owned();
+
+        let waiting = tokio::spawn({
+            let playlist = playlist.clone();
+            async move { wait_for_file(&playlist).await }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!waiting.is_finished());
+        // Empty files don't count; ffmpeg may not have written the playlist yet
+        std::fs::write(&playlist, "").unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!waiting.is_finished());
+        std::fs::write(&playlist, "#EXTM3U\n").unwrap();
+        tokio::time::timeout(Duration::from_secs(5), waiting).await.unwrap().unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
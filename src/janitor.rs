@@ -0,0 +1,463 @@
+use serde::Serialize;
+use std::collections::{BTreeSet, HashSet};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::process::Child;
+use tracing::{error, warn};
+
+use crate::config::Config;
+
+/// Session directory kinds, following the owning instance's prefix.
+const SESSION_DIR_KINDS: [&str; 2] = ["stream-", "proxyhl-"];
+
+/// Pid files are named `rtsp-proxy-<owner pid>.pids`.
+const PID_FILE_PREFIX: &str = "rtsp-proxy-";
+const PID_FILE_EXTENSION: &str = "pids";
+
+/// First line of a pid file, naming the server process that owns it.
+const OWNER_TAG: &str = "owner";
+
+/// How long orphaned ffmpeg processes get to exit after SIGTERM before they
+/// are killed.
+const TERM_GRACE: Duration = Duration::from_secs(3);
+
+/// What a cleanup pass removed.
+#[derive(Debug, Default, Serialize)]
+pub struct CleanupReport {
+    pub removed_dirs: Vec<String>,
+    pub killed_pids: Vec<u32>,
+}
+
+impl CleanupReport {
+    pub fn log(&self, context: &str) {
+        for pid in &self.killed_pids {
+            warn!("{}: terminated orphaned ffmpeg process {}", context, pid);
+        }
+        for dir in &self.removed_dirs {
+            warn!("{}: removed orphaned HLS directory {}", context, dir);
+        }
+    }
+}
+
+/// Prefix of the session directories created by server process `owner`, so
+/// instances sharing `hls.temp_dir` never sweep each other's directories.
+pub fn session_dir_prefix(owner: u32) -> String {
+    format!("hls-{}-", owner)
+}
+
+/// The pid file of server process `owner` under `dir`.
+pub fn pid_file(dir: &Path, owner: u32) -> PathBuf {
+    dir.join(format!("{}{}.{}", PID_FILE_PREFIX, owner, PID_FILE_EXTENSION))
+}
+
+/// Keeps the pids of every ffmpeg we spawned in a file of our own, so that
+/// the next start after a crash can terminate the processes left behind.
+#[derive(Clone)]
+pub struct ProcessTracker {
+    inner: Arc<TrackerInner>,
+}
+
+struct TrackerInner {
+    path: PathBuf,
+    owner: u32,
+    pids: Mutex<BTreeSet<u32>>,
+}
+
+/// Removes its pid from the tracker when dropped, i.e. once the owning code
+/// has killed or reaped the process.
+pub struct TrackedPid {
+    tracker: ProcessTracker,
+    pid: u32,
+}
+
+impl Drop for TrackedPid {
+    fn drop(&mut self) {
+        self.tracker.update(|pids| {
+            pids.remove(&self.pid);
+        });
+    }
+}
+
+impl ProcessTracker {
+    /// Start tracking into `path`, which must not exist yet: creating it is
+    /// what claims it for this process.
+    pub fn new(path: PathBuf) -> std::io::Result<Self> {
+        let owner = std::process::id();
+        let mut file = OpenOptions::new().write(true).create_new(true).open(&path)?;
+        writeln!(file, "{} {}", OWNER_TAG, owner)?;
+        file.sync_all()?;
+        Ok(Self {
+            inner: Arc::new(TrackerInner {
+                path,
+                owner,
+                pids: Mutex::new(BTreeSet::new()),
+            }),
+        })
+    }
+
+    /// Record a freshly spawned ffmpeg. Returns `None` if the child already
+    /// exited and has no pid.
+    pub fn track(&self, child: &Child) -> Option<TrackedPid> {
+        let pid = child.id()?;
+        self.update(|pids| {
+            pids.insert(pid);
+        });
+        Some(TrackedPid {
+            tracker: self.clone(),
+            pid,
+        })
+    }
+
+    fn update(&self, f: impl FnOnce(&mut BTreeSet<u32>)) {
+        let mut pids = self.inner.pids.lock().unwrap_or_else(|e| e.into_inner());
+        f(&mut pids);
+        if let Err(e) = write_pid_file(&self.inner.path, self.inner.owner, &pids) {
+            error!("Failed to write pid file {}: {}", self.inner.path.display(), e);
+        }
+    }
+}
+
+/// Write the pid file atomically: temp file, fsync, rename.
+fn write_pid_file(path: &Path, owner: u32, pids: &BTreeSet<u32>) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    {
+        let mut file = std::fs::File::create(&tmp)?;
+        writeln!(file, "{} {}", OWNER_TAG, owner)?;
+        for pid in pids {
+            writeln!(file, "{}", pid)?;
+        }
+        file.sync_all()?;
+    }
+    std::fs::rename(&tmp, path)
+}
+
+/// Clean up after previous runs that didn't shut down cleanly: terminate the
+/// ffmpeg processes each left running and remove its session directories.
+/// Instances that are still running, e.g. another server sharing the
+/// directories or one still draining during a restart, are left alone.
+///
+/// Must run before this process creates its [`ProcessTracker`].
+pub fn startup_cleanup(config: &Config) -> CleanupReport {
+    clean_up_dead_instances(&config.pid_dir(), &config.hls.temp_dir, std::process::id())
+}
+
+/// Clean up after every instance with a pid file in `pid_dir` whose owner is
+/// no longer running. `me` is the current process; a pid file naming it is
+/// left over from an earlier process that had the same pid.
+fn clean_up_dead_instances(pid_dir: &Path, temp_dir: &Path, me: u32) -> CleanupReport {
+    let mut report = CleanupReport::default();
+    let entries = match std::fs::read_dir(pid_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return report,
+        Err(e) => {
+            error!("Failed to scan {} for pid files: {}", pid_dir.display(), e);
+            return report;
+        }
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Some(owner) = pid_file_owner(&path) else {
+            continue;
+        };
+        if owner != me && is_running_server(owner) {
+            warn!("Skipping cleanup for {}: server process {} is still running", path.display(), owner);
+            continue;
+        }
+        // Returns once the processes exited, so they no longer write segments
+        report.killed_pids.extend(kill_orphaned_processes(&path));
+        report.removed_dirs.extend(remove_orphaned_dirs(
+            temp_dir,
+            &session_dir_prefix(owner),
+            &HashSet::new(),
+            Duration::ZERO,
+        ));
+        if let Err(e) = std::fs::remove_file(&path) {
+            error!("Failed to remove pid file {}: {}", path.display(), e);
+        }
+    }
+    report
+}
+
+/// The owner pid in a pid file's name, or `None` for any other file.
+fn pid_file_owner(path: &Path) -> Option<u32> {
+    if path.extension()? != PID_FILE_EXTENSION {
+        return None;
+    }
+    path.file_stem()?.to_str()?.strip_prefix(PID_FILE_PREFIX)?.parse().ok()
+}
+
+/// Terminate the ffmpeg processes listed in a pid file left by a previous run.
+/// Each gets SIGTERM first, so it can finish its segment and playlist writes;
+/// those still running after [`TERM_GRACE`] are killed.
+///
+/// Pids that no longer exist or now belong to something other than ffmpeg
+/// are skipped, so a recycled pid never takes down an unrelated process.
+pub fn kill_orphaned_processes(pid_file: &Path) -> Vec<u32> {
+    let contents = match std::fs::read_to_string(pid_file) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Vec::new(),
+        Err(e) => {
+            error!("Failed to read pid file {}: {}", pid_file.display(), e);
+            return Vec::new();
+        }
+    };
+
+    let pids: Vec<u32> = contents
+        .lines()
+        .filter(|line| !line.starts_with(OWNER_TAG))
+        .filter_map(|line| line.trim().parse::<u32>().ok())
+        .filter(|&pid| is_ffmpeg(pid))
+        .filter(|&pid| match terminate(pid, false) {
+            Ok(()) => true,
+            Err(e) => {
+                error!("Failed to terminate orphaned ffmpeg {}: {}", pid, e);
+                false
+            }
+        })
+        .collect();
+
+    let deadline = Instant::now() + TERM_GRACE;
+    while pids.iter().any(|&pid| is_ffmpeg(pid)) && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    for &pid in pids.iter().filter(|&&pid| is_ffmpeg(pid)) {
+        warn!("Orphaned ffmpeg {} ignored SIGTERM for {}s; killing it", pid, TERM_GRACE.as_secs());
+        if let Err(e) = terminate(pid, true) {
+            error!("Failed to kill orphaned ffmpeg {}: {}", pid, e);
+        }
+    }
+    pids
+}
+
+/// Delete the session directories under `temp_dir` that start with `prefix`
+/// (see [`session_dir_prefix`]) and don't belong to a live session.
+///
+/// Directories modified within `min_age` are kept: a session's directory is
+/// created shortly before the session is registered, and a directory that is
+/// still being written to has an ffmpeg behind it.
+pub fn remove_orphaned_dirs(
+    temp_dir: &Path,
+    prefix: &str,
+    active: &HashSet<PathBuf>,
+    min_age: Duration,
+) -> Vec<String> {
+    let entries = match std::fs::read_dir(temp_dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("Failed to scan {} for orphaned HLS directories: {}", temp_dir.display(), e);
+            return Vec::new();
+        }
+    };
+
+    let mut removed = Vec::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        let Some(kind) = name.strip_prefix(prefix) else {
+            continue;
+        };
+        if !SESSION_DIR_KINDS.iter().any(|k| kind.starts_with(k)) {
+            continue;
+        }
+        let path = entry.path();
+        if active.contains(&path) || !path.is_dir() {
+            continue;
+        }
+        let age = entry
+            .metadata()
+            .and_then(|meta| meta.modified())
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+            .unwrap_or_default();
+        if age < min_age {
+            continue;
+        }
+        match std::fs::remove_dir_all(&path) {
+            Ok(()) => removed.push(path.to_string_lossy().into_owned()),
+            Err(e) => error!("Failed to remove orphaned HLS directory {}: {}", path.display(), e),
+        }
+    }
+    removed
+}
+
+fn is_ffmpeg(pid: u32) -> bool {
+    process_name(pid).is_some_and(|name| name.to_lowercase().contains("ffmpeg"))
+}
+
+/// Whether `pid` is running the same program as this process; a recycled pid
+/// that now belongs to something else doesn't keep a dead instance's pid file
+/// from being cleaned up.
+fn is_running_server(pid: u32) -> bool {
+    let Some(name) = process_name(pid) else {
+        return false;
+    };
+    process_name(std::process::id()).is_some_and(|me| me == name)
+}
+
+#[cfg(unix)]
+fn process_name(pid: u32) -> Option<String> {
+    let out = std::process::Command::new("ps")
+        .args(["-p", &pid.to_string(), "-o", "comm="])
+        .output()
+        .ok()?;
+    let name = String::from_utf8_lossy(&out.stdout).trim().to_string();
+    (out.status.success() && !name.is_empty()).then_some(name)
+}
+
+#[cfg(windows)]
+fn process_name(pid: u32) -> Option<String> {
+    let out = std::process::Command::new("tasklist")
+        .args(["/FI", &format!("PID eq {}", pid), "/FO", "CSV", "/NH"])
+        .output()
+        .ok()?;
+    // `"ffmpeg.exe","1234",...`, or an informational line without quotes
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    let name = stdout.strip_prefix('"')?.split('"').next()?;
+    Some(name.to_string())
+}
+
+/// Send SIGTERM, or SIGKILL when `force` is set.
+#[cfg(unix)]
+fn terminate(pid: u32, force: bool) -> std::io::Result<()> {
+    let signal = if force { "-KILL" } else { "-TERM" };
+    let status = std::process::Command::new("kill")
+        .args([signal, &pid.to_string()])
+        .status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(std::io::Error::other(format!("kill exited with {}", status)))
+    }
+}
+
+/// Ask the process to close, or end it when `force` is set.
+#[cfg(windows)]
+fn terminate(pid: u32, force: bool) -> std::io::Result<()> {
+    let pid = pid.to_string();
+    let mut args = vec!["/PID", pid.as_str()];
+    if force {
+        args.push("/F");
+    }
+    let status = std::process::Command::new("taskkill").args(&args).status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(std::io::Error::other(format!("taskkill exited with {}", status)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("{}-{}", name, Uuid::new_v4()))
+    }
+
+    #[test]
+    fn removes_only_inactive_session_dirs() {
+        let temp_dir = temp_path("rtsp-proxy-janitor");
+        for dir in [
+            "hls-7-stream-live",
+            "hls-7-stream-gone",
+            "hls-7-proxyhl-gone",
+            "hls-8-stream-other-instance",
+            "hls-7-other",
+            "unrelated",
+        ] {
+            std::fs::create_dir_all(temp_dir.join(dir)).unwrap();
+        }
+        std::fs::write(temp_dir.join("hls-7-stream-file"), b"not a dir").unwrap();
+        let active = HashSet::from([temp_dir.join("hls-7-stream-live")]);
+        let prefix = session_dir_prefix(7);
+
+        // Everything is fresh, so nothing is old enough yet
+        assert!(remove_orphaned_dirs(&temp_dir, &prefix, &active, Duration::from_secs(3600)).is_empty());
+
+        let mut removed = remove_orphaned_dirs(&temp_dir, &prefix, &active, Duration::ZERO);
+        removed.sort();
+        assert_eq!(
+            removed,
+            [
+                temp_dir.join("hls-7-proxyhl-gone").to_string_lossy(),
+                temp_dir.join("hls-7-stream-gone").to_string_lossy(),
+            ]
+        );
+        for kept in [
+            "hls-7-stream-live",
+            "hls-8-stream-other-instance",
+            "hls-7-other",
+            "unrelated",
+            "hls-7-stream-file",
+        ] {
+            assert!(temp_dir.join(kept).exists(), "{} was removed", kept);
+        }
+        std::fs::remove_dir_all(&temp_dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn pid_file_follows_tracked_processes() {
+        let pid_file = temp_path("rtsp-proxy-pids");
+        let owner = format!("owner {}\n", std::process::id());
+        let tracker = ProcessTracker::new(pid_file.clone()).unwrap();
+        assert_eq!(std::fs::read_to_string(&pid_file).unwrap(), owner);
+        // Another tracker can't claim the same file
+        assert!(ProcessTracker::new(pid_file.clone()).is_err());
+
+        let mut child = tokio::process::Command::new("sleep")
+            .arg("30")
+            .kill_on_drop(true)
+            .spawn()
+            .unwrap();
+        let tracked = tracker.track(&child).unwrap();
+        let pid = child.id().unwrap();
+        assert_eq!(std::fs::read_to_string(&pid_file).unwrap(), format!("{}{}\n", owner, pid));
+
+        child.kill().await.unwrap();
+        drop(tracked);
+        assert_eq!(std::fs::read_to_string(&pid_file).unwrap(), owner);
+        // Not ffmpeg, so never signalled
+        std::fs::write(&pid_file, format!("{}{}\n", owner, std::process::id())).unwrap();
+        assert!(kill_orphaned_processes(&pid_file).is_empty());
+        std::fs::remove_file(&pid_file).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn startup_cleanup_skips_running_instances() {
+        let dir = temp_path("rtsp-proxy-instances");
+        std::fs::create_dir_all(&dir).unwrap();
+        // A pid that no longer runs anything
+        let mut exited = std::process::Command::new("true").spawn().unwrap();
+        let dead = exited.id();
+        exited.wait().unwrap();
+        let running = std::process::id();
+        for owner in [dead, running] {
+            std::fs::write(pid_file(&dir, owner), format!("owner {}\n", owner)).unwrap();
+            std::fs::create_dir_all(dir.join(format!("{}stream-a", session_dir_prefix(owner)))).unwrap();
+        }
+
+        // Pretend to be a new process, so this test process is another
+        // instance that is still running
+        let report = clean_up_dead_instances(&dir, &dir, u32::MAX);
+        assert_eq!(
+            report.removed_dirs,
+            [dir.join(format!("{}stream-a", session_dir_prefix(dead))).to_string_lossy()]
+        );
+        assert!(!pid_file(&dir, dead).exists());
+        assert!(pid_file(&dir, running).exists());
+        assert!(dir.join(format!("{}stream-a", session_dir_prefix(running))).exists());
+
+        // A pid file naming the current process is left from an earlier one
+        let report = clean_up_dead_instances(&dir, &dir, running);
+        assert_eq!(report.removed_dirs.len(), 1);
+        assert!(!pid_file(&dir, running).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,88 @@
+use anyhow::{anyhow, Result};
+use axum::Router;
+use std::path::{Path, PathBuf};
+
+use crate::shutdown::Shutdown;
+
+#[cfg(unix)]
+pub use unix::{bind_unix, serve_unix};
+
+#[cfg(unix)]
+mod unix {
+    use super::*;
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use hyper_util::server::conn::auto;
+    use hyper_util::service::TowerToHyperService;
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    use std::time::Duration;
+    use tokio::net::UnixListener;
+    use tracing::{debug, warn};
+
+    /// Bind a Unix domain socket with `mode` permissions. A socket left
+    /// behind by an unclean exit is replaced; any other file is an error.
+    pub fn bind_unix(path: &Path, mode: u32) -> Result<UnixListener> {
+        match std::fs::symlink_metadata(path) {
+            Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)?,
+            Ok(_) => return Err(anyhow!("{} exists and is not a socket", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+        let listener = UnixListener::bind(path)
+            .map_err(|e| anyhow!("Failed to bind {}: {}", path.display(), e))?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        Ok(listener)
+    }
+
+    /// Serve `router` on a Unix socket until the server starts stopping,
+    /// then remove the socket file. Open connections finish on their own.
+    pub async fn serve_unix(listener: UnixListener, path: PathBuf, router: Router, shutdown: Shutdown) {
+        let stopping = shutdown.stopping();
+        tokio::pin!(stopping);
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = &mut stopping => break,
+            };
+            let stream = match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    // e.g. out of file descriptors; don't spin
+                    warn!("Failed to accept on {}: {}", path.display(), e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let service = TowerToHyperService::new(router.clone());
+            tokio::spawn(async move {
+                if let Err(e) = auto::Builder::new(TokioExecutor::new())
+                    .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                    .await
+                {
+                    debug!("Admin socket connection ended: {}", e);
+                }
+            });
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn bind_unix(path: &Path, _mode: u32) -> Result<std::convert::Infallible> {
+    Err(anyhow!(
+        "Cannot listen on {}: Unix domain sockets are not supported on this platform",
+        path.display()
+    ))
+}
+
+#[cfg(not(unix))]
+pub async fn serve_unix(
+    listener: std::convert::Infallible,
+    _path: PathBuf,
+    _router: Router,
+    _shutdown: Shutdown,
+) {
+    match listener {}
+}
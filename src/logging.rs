@@ -0,0 +1,192 @@
+use anyhow::Result;
+use axum::{
+    extract::Request,
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tracing::{field, info, info_span, Instrument, Span};
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, Registry};
+use uuid::Uuid;
+
+use crate::config::{LogFormat, LoggingConfig};
+
+/// Carries the request id in both directions.
+const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+#[derive(Debug, Error)]
+pub enum LogError {
+    #[error("Invalid log filter: {0}")]
+    Invalid(String),
+    #[error("Failed to apply log filter: {0}")]
+    Reload(String),
+}
+
+/// Logging settings given on the command line; they win over `[logging]`,
+/// including after a reload.
+#[derive(Debug, Default)]
+pub struct LogOverrides {
+    pub level: Option<LevelFilter>,
+    pub filter: Option<String>,
+    pub format: Option<LogFormat>,
+}
+
+/// Changes the log filter of the running process.
+#[derive(Clone)]
+pub struct LogControl {
+    handle: reload::Handle<EnvFilter, Registry>,
+    overrides: Arc<LogOverrides>,
+    /// Directives of the filter in use
+    current: Arc<Mutex<String>>,
+}
+
+/// Install the global subscriber.
+pub fn init(config: &LoggingConfig, overrides: LogOverrides) -> Result<LogControl> {
+    let directives = directives(config, &overrides);
+    let filter = parse(&directives)?;
+    let (filter, handle) = reload::Layer::new(filter);
+    let registry = tracing_subscriber::registry().with(filter);
+    match overrides.format.unwrap_or(config.format) {
+        LogFormat::Text => registry.with(fmt::layer()).try_init()?,
+        LogFormat::Json => registry
+            .with(fmt::layer().json().with_current_span(true).with_span_list(false))
+            .try_init()?,
+    }
+    Ok(LogControl {
+        handle,
+        overrides: Arc::new(overrides),
+        current: Arc::new(Mutex::new(directives)),
+    })
+}
+
+/// Command-line filter, then command-line level, then the config file.
+fn directives(config: &LoggingConfig, overrides: &LogOverrides) -> String {
+    match (&overrides.filter, overrides.level) {
+        (Some(filter), _) => filter.clone(),
+        (None, Some(level)) => level.to_string().to_lowercase(),
+        (None, None) => config.directives(),
+    }
+}
+
+fn parse(directives: &str) -> Result<EnvFilter, LogError> {
+    EnvFilter::try_new(directives).map_err(|e| LogError::Invalid(format!("\"{}\": {}", directives, e)))
+}
+
+impl LogControl {
+    /// A control whose filter isn't installed anywhere, for code that only
+    /// needs one to hand.
+    #[cfg(test)]
+    pub fn detached() -> Self {
+        let (_, handle) = reload::Layer::<EnvFilter, Registry>::new(EnvFilter::new("info"));
+        Self {
+            handle,
+            overrides: Arc::new(LogOverrides::default()),
+            current: Arc::new(Mutex::new("info".to_string())),
+        }
+    }
+
+    pub fn filter(&self) -> String {
+        self.current.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Replace the filter until the next restart, or the next reload that
+    /// changes `[logging]`.
+    pub fn set_filter(&self, directives: &str) -> Result<(), LogError> {
+        let filter = parse(directives)?;
+        let mut current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+        self.handle
+            .reload(filter)
+            .map_err(|e| LogError::Reload(e.to_string()))?;
+        *current = directives.to_string();
+        info!("Log filter set to \"{}\"", directives);
+        Ok(())
+    }
+
+    /// Apply a reloaded `[logging]` section; command-line overrides still win.
+    pub fn apply_config(&self, config: &LoggingConfig) -> Result<(), LogError> {
+        self.set_filter(&directives(config, &self.overrides))
+    }
+}
+
+/// Run each request in a span carrying its id, which is taken from
+/// `X-Request-Id` when the caller sends a sane one and echoed back. Handlers
+/// add the stream or session they act on with `record_stream` and
+/// `record_session`.
+pub async fn request_span(request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(&REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| is_valid_request_id(id))
+        .map_or_else(|| Uuid::new_v4().to_string(), str::to_string);
+    // The path only: queries may carry source URLs with logins
+    let span = info_span!(
+        "request",
+        id = %id,
+        method = %request.method(),
+        path = %request.uri().path(),
+        stream = field::Empty,
+        session = field::Empty,
+    );
+    let mut response = next.run(request).instrument(span).await;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID, value);
+    }
+    response
+}
+
+/// Short, and nothing that could forge a log line or field.
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+}
+
+/// Tag the current request's span with the managed stream it is for.
+pub fn record_stream(id: &str) {
+    Span::current().record("stream", id);
+}
+
+/// Tag the current request's span with the HLS session it is for.
+pub fn record_session(id: &str) {
+    Span::current().record("session", id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_line_wins_over_the_config_file() {
+        let mut config = LoggingConfig::default();
+        assert_eq!(directives(&config, &LogOverrides::default()), "info");
+        config.filter = Some("warn,rtsp_proxy=debug".to_string());
+        assert_eq!(directives(&config, &LogOverrides::default()), "warn,rtsp_proxy=debug");
+
+        let level = LogOverrides {
+            level: Some(LevelFilter::DEBUG),
+            ..Default::default()
+        };
+        assert_eq!(directives(&config, &level), "debug");
+        let filter = LogOverrides {
+            level: Some(LevelFilter::DEBUG),
+            filter: Some("trace".to_string()),
+            format: None,
+        };
+        assert_eq!(directives(&config, &filter), "trace");
+    }
+
+    #[test]
+    fn request_ids_are_checked() {
+        assert!(is_valid_request_id("3f2a9c1e-7b4d-4e8a-9f0b-1c2d3e4f5a6b"));
+        assert!(is_valid_request_id("lb.01_abc"));
+        assert!(!is_valid_request_id(""));
+        assert!(!is_valid_request_id("id\" level=error"));
+        assert!(!is_valid_request_id(&"a".repeat(65)));
+    }
+}
@@ -1,13 +1,47 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::Parser;
+use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::sync::RwLock;
-use tracing::{info, Level};
+use tracing::{info, warn};
+use tracing_subscriber::filter::LevelFilter;
 
+mod admission;
+mod atomic_file;
+mod auth;
+mod capabilities;
+mod config;
+mod cors;
+mod credentials;
+mod health;
+mod hls_session;
+mod janitor;
+mod listen;
+mod logging;
+mod metrics;
+mod playback;
+mod probe;
+mod rate_limit;
+mod redact;
+mod registry;
+mod reload;
+mod renditions;
 mod rtsp_client;
+mod shutdown;
+mod sources;
 mod streaming_server;
 mod stream_manager;
+mod tls;
 
+use admission::Admission;
+use capabilities::Capabilities;
+use config::{Config, LogFormat, SharedConfig};
+use credentials::CredentialStore;
+use janitor::ProcessTracker;
+use logging::LogOverrides;
+use metrics::Metrics;
+use registry::StreamRegistry;
+use reload::ConfigReloader;
 use stream_manager::StreamManager;
 use streaming_server::StreamingServer;
 
@@ -15,32 +49,133 @@ use streaming_server::StreamingServer;
 #[command(name = "rtsp-proxy")]
 #[command(about = "RTSP to HLS/MPEG-TS proxy server", long_about = None)]
 struct Args {
-    /// HTTP server port
-    #[arg(short, long, default_value = "5000")]
-    port: u16,
+    /// Path to a TOML configuration file
+    #[arg(short, long)]
+    config: Option<PathBuf>,
 
-    /// Host to bind to
-    #[arg(long, default_value = "0.0.0.0")]
-    host: String,
+    /// HTTP server port (default: 5000, overrides the config file)
+    #[arg(short, long)]
+    port: Option<u16>,
+
+    /// Host to bind to (default: 0.0.0.0, overrides the config file)
+    #[arg(long)]
+    host: Option<String>,
+
+    /// Log level: error, warn, info, debug, trace or off (default: info,
+    /// overrides the config file)
+    #[arg(long)]
+    log_level: Option<LevelFilter>,
+
+    /// Log filter directives, e.g. "info,rtsp_proxy::hls_session=debug";
+    /// takes precedence over --log-level
+    #[arg(long)]
+    log_filter: Option<String>,
+
+    /// Log output format (default: text, overrides the config file)
+    #[arg(long, value_enum)]
+    log_format: Option<LogFormat>,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize tracing
-    tracing_subscriber::fmt()
-        .with_max_level(Level::WARN)
-        .init();
-
     let args = Args::parse();
 
+    let mut config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    if let Some(port) = args.port {
+        config.server.port = port;
+    }
+    if let Some(host) = &args.host {
+        config.server.host = host.clone();
+    }
+
+    let logs = logging::init(
+        &config.logging,
+        LogOverrides {
+            level: args.log_level,
+            filter: args.log_filter.clone(),
+            format: args.log_format,
+        },
+    )?;
+
     info!("Starting RTSP Proxy Server");
-    info!("Server will listen on {}:{}", args.host, args.port);
+    info!("Server will listen on {}:{}", config.server.host, config.server.port);
+
+    // Swap encoders the installed ffmpeg lacks for fallbacks, and refuse
+    // profiles it can't encode at all
+    let capabilities = match Capabilities::detect(&config.ffmpeg.path).await {
+        Ok(capabilities) => {
+            info!("Using ffmpeg {} at {}", capabilities.version, config.ffmpeg.path.display());
+            for warning in capabilities.apply(&mut config) {
+                warn!("{}", warning);
+            }
+            Some(capabilities)
+        }
+        Err(e) => {
+            warn!("Couldn't query ffmpeg, so encoders are not checked: {}", e);
+            None
+        }
+    };
+    let config = Arc::new(config);
+
+    // Terminate ffmpeg processes and remove HLS directories a crashed run left behind
+    janitor::startup_cleanup(&config).log("Startup cleanup");
+    let pid_file = config.pid_file();
+    let processes = ProcessTracker::new(pid_file.clone())
+        .with_context(|| format!("Failed to create pid file {}", pid_file.display()))?;
+
+    let registry = match &config.server.registry_path {
+        Some(path) => Some(StreamRegistry::load(path)?),
+        None => None,
+    };
+
+    // Camera logins referenced by name instead of travelling in URLs
+    let credentials = CredentialStore::open(&config.credentials)?;
+
+    let shared_config = SharedConfig::new(config.clone());
+    let admission = Admission::new(shared_config.clone());
+    let metrics = Metrics::new();
+
+    // Create stream manager, restore persisted streams and bring up the
+    // streams declared in the config
+    let stream_manager = Arc::new(RwLock::new(StreamManager::new(
+        config,
+        registry,
+        processes.clone(),
+        admission.clone(),
+        credentials.clone(),
+        metrics.clone(),
+    )));
+    {
+        let mut manager = stream_manager.write().await;
+        manager.restore_streams().await;
+        manager.start_configured_streams().await;
+    }
 
-    // Create stream manager
-    let stream_manager = Arc::new(RwLock::new(StreamManager::new()));
+    // Reload configuration on SIGHUP and via the admin API
+    let reloader = Arc::new(ConfigReloader::new(
+        args.config,
+        args.host,
+        args.port,
+        shared_config.clone(),
+        stream_manager.clone(),
+        logs,
+        capabilities,
+    ));
+    reload::spawn_sighup_handler(reloader.clone());
 
     // Start HTTP server
-    let server = StreamingServer::new(args.host, args.port, stream_manager);
+    let server = StreamingServer::new(
+        shared_config,
+        stream_manager,
+        reloader,
+        processes,
+        admission,
+        credentials,
+        metrics,
+    );
     server.run().await?;
 
     Ok(())
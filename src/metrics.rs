@@ -0,0 +1,416 @@
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use prometheus_client::encoding::{text, EncodeLabelSet};
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::Registry;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How many ffmpeg failures `recent_failures` remembers.
+const RECENT_FAILURES: usize = 20;
+
+/// Content type of the exposition `render` produces.
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Which part of the server an ffmpeg process was started for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FfmpegKind {
+    /// A managed stream's MPEG-TS feed
+    Managed,
+    /// An HLS session
+    Hls,
+    /// A `/stream` response
+    Direct,
+    /// A `/proxy/rtsp` MJPEG response
+    Mjpeg,
+}
+
+impl FfmpegKind {
+    fn name(self) -> &'static str {
+        match self {
+            FfmpegKind::Managed => "managed",
+            FfmpegKind::Hls => "hls",
+            FfmpegKind::Direct => "direct",
+            FfmpegKind::Mjpeg => "mjpeg",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct HttpLabels {
+    route: String,
+    method: String,
+    status: u16,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RouteLabels {
+    route: String,
+    method: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct KindLabels {
+    kind: &'static str,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ServedLabels {
+    source: &'static str,
+    /// Managed stream id, or `-` for sessions and ad-hoc sources, which would
+    /// otherwise add a series per viewer or URL
+    stream: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct NvrLabels {
+    route: String,
+    outcome: &'static str,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct BudgetLabels {
+    budget: &'static str,
+}
+
+/// An ffmpeg failure as reported by `/api/diagnostics`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Failure {
+    /// Unix time
+    pub at: u64,
+    pub kind: &'static str,
+    pub detail: String,
+}
+
+/// Process-wide Prometheus metrics. Counters are updated where things
+/// happen; gauges are filled in from live state by `set_gauges` before each
+/// scrape. The last few ffmpeg failures are kept alongside for diagnostics.
+#[derive(Clone)]
+pub struct Metrics {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    registry: Registry,
+    http_requests: Family<HttpLabels, Counter>,
+    http_duration: Family<RouteLabels, Histogram, fn() -> Histogram>,
+    managed_streams: Gauge,
+    hls_sessions: Family<KindLabels, Gauge>,
+    viewers: Family<KindLabels, Gauge>,
+    bytes_served: Family<ServedLabels, Counter>,
+    ffmpeg_spawns: Family<KindLabels, Counter>,
+    ffmpeg_restarts: Family<KindLabels, Counter>,
+    ffmpeg_failures: Family<KindLabels, Counter>,
+    hls_ready: Histogram,
+    hls_idle_reaps: Counter,
+    nvr_requests: Family<NvrLabels, Counter>,
+    rate_limited: Family<BudgetLabels, Counter>,
+    recent_failures: Mutex<VecDeque<Failure>>,
+}
+
+/// Gauge values read from live state at scrape time.
+pub struct Snapshot {
+    pub managed_streams: usize,
+    pub hls_sessions: usize,
+    pub proxyhl_sessions: usize,
+    pub hls_viewers: usize,
+    /// Open MPEG-TS and MJPEG responses
+    pub stream_viewers: usize,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let mut inner = Inner {
+            registry: Registry::with_prefix("rtsp_proxy"),
+            http_requests: Family::default(),
+            http_duration: Family::new_with_constructor(|| Histogram::new(exponential_buckets(0.005, 2.0, 12))),
+            managed_streams: Gauge::default(),
+            hls_sessions: Family::default(),
+            viewers: Family::default(),
+            bytes_served: Family::default(),
+            ffmpeg_spawns: Family::default(),
+            ffmpeg_restarts: Family::default(),
+            ffmpeg_failures: Family::default(),
+            hls_ready: Histogram::new(exponential_buckets(0.5, 2.0, 7)),
+            hls_idle_reaps: Counter::default(),
+            nvr_requests: Family::default(),
+            rate_limited: Family::default(),
+            recent_failures: Mutex::new(VecDeque::with_capacity(RECENT_FAILURES)),
+        };
+
+        inner.registry.register(
+            "http_requests",
+            "HTTP requests by matched route, method and status",
+            inner.http_requests.clone(),
+        );
+        inner.registry.register(
+            "http_request_duration_seconds",
+            "Time until response headers, by matched route and method",
+            inner.http_duration.clone(),
+        );
+        inner.registry.register(
+            "managed_streams",
+            "Managed streams currently running",
+            inner.managed_streams.clone(),
+        );
+        inner.registry.register(
+            "hls_sessions",
+            "Live HLS sessions by kind",
+            inner.hls_sessions.clone(),
+        );
+        inner.registry.register(
+            "viewers",
+            "Live viewers: clients of HLS sessions, and open MPEG-TS or MJPEG responses",
+            inner.viewers.clone(),
+        );
+        inner.registry.register(
+            "bytes_served",
+            "Media bytes sent to viewers, by source and managed stream id",
+            inner.bytes_served.clone(),
+        );
+        inner.registry.register(
+            "ffmpeg_spawns",
+            "ffmpeg processes started",
+            inner.ffmpeg_spawns.clone(),
+        );
+        inner.registry.register(
+            "ffmpeg_restarts",
+            "ffmpeg processes replaced by a restart",
+            inner.ffmpeg_restarts.clone(),
+        );
+        inner.registry.register(
+            "ffmpeg_failures",
+            "ffmpeg processes that failed to start, exited with an error or never became ready",
+            inner.ffmpeg_failures.clone(),
+        );
+        inner.registry.register(
+            "hls_session_ready_seconds",
+            "Time from starting an HLS session until its first playlist is written",
+            inner.hls_ready.clone(),
+        );
+        inner.registry.register(
+            "hls_idle_reaps",
+            "HLS sessions stopped because no viewer was left",
+            inner.hls_idle_reaps.clone(),
+        );
+        inner.registry.register(
+            "nvr_requests",
+            "NVR proxy requests by route and outcome",
+            inner.nvr_requests.clone(),
+        );
+        inner.registry.register(
+            "rate_limited_requests",
+            "Requests answered 429, by rate limit budget",
+            inner.rate_limited.clone(),
+        );
+
+        Self {
+            inner: Arc::new(inner),
+        }
+    }
+
+    /// Count media bytes sent; `stream` is the managed stream id, if any.
+    pub fn bytes_served(&self, source: &'static str, stream: Option<&str>, bytes: usize) {
+        self.inner
+            .bytes_served
+            .get_or_create(&ServedLabels {
+                source,
+                stream: stream.unwrap_or("-").to_string(),
+            })
+            .inc_by(bytes as u64);
+    }
+
+    pub fn ffmpeg_spawned(&self, kind: FfmpegKind) {
+        self.inner.ffmpeg_spawns.get_or_create(&KindLabels { kind: kind.name() }).inc();
+    }
+
+    pub fn ffmpeg_restarted(&self, kind: FfmpegKind) {
+        self.inner.ffmpeg_restarts.get_or_create(&KindLabels { kind: kind.name() }).inc();
+    }
+
+    /// Count a failure and remember it; `detail` must not hold credentials.
+    pub fn ffmpeg_failed(&self, kind: FfmpegKind, detail: String) {
+        self.inner.ffmpeg_failures.get_or_create(&KindLabels { kind: kind.name() }).inc();
+        let mut recent = self.inner.recent_failures.lock().unwrap_or_else(|e| e.into_inner());
+        if recent.len() == RECENT_FAILURES {
+            recent.pop_front();
+        }
+        recent.push_back(Failure {
+            at: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            kind: kind.name(),
+            detail,
+        });
+    }
+
+    /// The latest ffmpeg failures, newest first.
+    pub fn recent_failures(&self) -> Vec<Failure> {
+        let recent = self.inner.recent_failures.lock().unwrap_or_else(|e| e.into_inner());
+        recent.iter().rev().cloned().collect()
+    }
+
+    pub fn hls_session_ready(&self, elapsed: Duration) {
+        self.inner.hls_ready.observe(elapsed.as_secs_f64());
+    }
+
+    pub fn hls_idle_reaped(&self) {
+        self.inner.hls_idle_reaps.inc();
+    }
+
+    pub fn rate_limited(&self, budget: &'static str) {
+        self.inner.rate_limited.get_or_create(&BudgetLabels { budget }).inc();
+    }
+
+    pub fn set_gauges(&self, snapshot: &Snapshot) {
+        let gauge = |family: &Family<KindLabels, Gauge>, kind, value: usize| {
+            family.get_or_create(&KindLabels { kind }).set(value as i64);
+        };
+        self.inner.managed_streams.set(snapshot.managed_streams as i64);
+        gauge(&self.inner.hls_sessions, "stream", snapshot.hls_sessions);
+        gauge(&self.inner.hls_sessions, "proxyhl", snapshot.proxyhl_sessions);
+        gauge(&self.inner.viewers, "hls", snapshot.hls_viewers);
+        gauge(&self.inner.viewers, "stream", snapshot.stream_viewers);
+    }
+
+    /// The text exposition of every metric.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        // Writing into a String can't fail
+        let _ = text::encode(&mut out, &self.inner.registry);
+        out
+    }
+}
+
+/// Route label for a request: the route pattern it matched, so ids in paths
+/// don't create a series each.
+fn route(request: &Request) -> String {
+    request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", |path| path.as_str())
+        .to_string()
+}
+
+/// Count every request and time it until the response headers are ready;
+/// streaming bodies are counted in `bytes_served` instead.
+pub async fn track_http(State(metrics): State<Metrics>, request: Request, next: Next) -> Response {
+    let labels = RouteLabels {
+        route: route(&request),
+        method: request.method().to_string(),
+    };
+    let started = Instant::now();
+    let response = next.run(request).await;
+    metrics
+        .inner
+        .http_duration
+        .get_or_create(&labels)
+        .observe(started.elapsed().as_secs_f64());
+    metrics
+        .inner
+        .http_requests
+        .get_or_create(&HttpLabels {
+            route: labels.route,
+            method: labels.method,
+            status: response.status().as_u16(),
+        })
+        .inc();
+    response
+}
+
+/// Count NVR proxy requests by how they ended.
+pub async fn track_nvr(State(metrics): State<Metrics>, request: Request, next: Next) -> Response {
+    let route = route(&request);
+    let response = next.run(request).await;
+    metrics
+        .inner
+        .nvr_requests
+        .get_or_create(&NvrLabels {
+            route,
+            outcome: nvr_outcome(response.status()),
+        })
+        .inc();
+    response
+}
+
+fn nvr_outcome(status: StatusCode) -> &'static str {
+    match status {
+        s if s.is_success() || s.is_redirection() => "ok",
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => "denied",
+        StatusCode::TOO_MANY_REQUESTS => "rate_limited",
+        StatusCode::BAD_GATEWAY | StatusCode::GATEWAY_TIMEOUT => "upstream_error",
+        StatusCode::SERVICE_UNAVAILABLE => "unavailable",
+        s if s.is_client_error() => "bad_request",
+        _ => "error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_counters_and_gauges() {
+        let metrics = Metrics::new();
+        metrics.ffmpeg_spawned(FfmpegKind::Hls);
+        for i in 0..=RECENT_FAILURES {
+            metrics.ffmpeg_failed(FfmpegKind::Direct, format!("failure {}", i));
+        }
+        metrics.bytes_served("managed", Some("lobby"), 1316);
+        metrics.bytes_served("hls", None, 940);
+        metrics.bytes_served("hls", None, 60);
+        metrics.bytes_served("direct", None, 188);
+        metrics.set_gauges(&Snapshot {
+            managed_streams: 2,
+            hls_sessions: 1,
+            proxyhl_sessions: 0,
+            hls_viewers: 3,
+            stream_viewers: 1,
+        });
+
+        let text = metrics.render();
+        assert!(text.contains("rtsp_proxy_ffmpeg_spawns_total{kind=\"hls\"} 1"), "{}", text);
+        assert!(
+            text.contains("rtsp_proxy_bytes_served_total{source=\"managed\",stream=\"lobby\"} 1316"),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("rtsp_proxy_bytes_served_total{source=\"hls\",stream=\"-\"} 1000"),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("rtsp_proxy_bytes_served_total{source=\"direct\",stream=\"-\"} 188"),
+            "{}",
+            text
+        );
+        assert!(text.contains("rtsp_proxy_managed_streams 2"), "{}", text);
+        assert!(text.contains("rtsp_proxy_viewers{kind=\"hls\"} 3"), "{}", text);
+        assert!(text.contains("rtsp_proxy_ffmpeg_failures_total{kind=\"direct\"} 21"), "{}", text);
+        assert!(text.ends_with("# EOF\n"));
+
+        let recent = metrics.recent_failures();
+        assert_eq!(recent.len(), RECENT_FAILURES);
+        assert_eq!(recent[0].detail, format!("failure {}", RECENT_FAILURES));
+    }
+
+    #[test]
+    fn nvr_outcomes_follow_the_status() {
+        assert_eq!(nvr_outcome(StatusCode::OK), "ok");
+        assert_eq!(nvr_outcome(StatusCode::FOUND), "ok");
+        assert_eq!(nvr_outcome(StatusCode::FORBIDDEN), "denied");
+        assert_eq!(nvr_outcome(StatusCode::BAD_GATEWAY), "upstream_error");
+        assert_eq!(nvr_outcome(StatusCode::BAD_REQUEST), "bad_request");
+        assert_eq!(nvr_outcome(StatusCode::INTERNAL_SERVER_ERROR), "error");
+    }
+}
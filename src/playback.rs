@@ -0,0 +1,321 @@
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Path, Query, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ring::hmac;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tracing::warn;
+
+use crate::auth::{self, Access, Scope};
+use crate::config::{PlaybackConfig, SharedConfig};
+
+/// Playlists are a few KiB; anything bigger isn't one of ours.
+const MAX_PLAYLIST_BYTES: usize = 1024 * 1024;
+const PLAYLIST_CONTENT_TYPE: &str = "application/vnd.apple.mpegurl";
+
+/// What a token grants access to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceKind {
+    /// An on-demand HLS session, `/stream/hls/{id}/` or `/proxyhl/segment/{id}/`
+    Session,
+    /// A managed stream, `/stream/{id}/...`
+    Stream,
+}
+
+impl ResourceKind {
+    fn tag(self) -> &'static str {
+        match self {
+            ResourceKind::Session => "session",
+            ResourceKind::Stream => "stream",
+        }
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum TokenError {
+    #[error("Malformed playback token")]
+    Malformed,
+    #[error("Invalid playback token")]
+    BadSignature,
+    #[error("Playback token has expired")]
+    Expired,
+    #[error("Playback token was issued to a different address")]
+    WrongAddress,
+}
+
+/// Sign a token for `id`, valid until `expires` (Unix seconds) and, if
+/// `client` is set, only from that address.
+///
+/// The token is `base64(expires|ip).base64(hmac)`; the resource is part of
+/// the signed message rather than the token, so a token for one session
+/// can't be replayed against another.
+pub fn sign(secret: &str, kind: ResourceKind, id: &str, expires: u64, client: Option<IpAddr>) -> String {
+    let payload = format!("{}|{}", expires, client.map(|ip| ip.to_string()).unwrap_or_default());
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let signature = hmac::sign(&key, message(kind, id, &payload).as_bytes());
+    format!(
+        "{}.{}",
+        URL_SAFE_NO_PAD.encode(payload),
+        URL_SAFE_NO_PAD.encode(signature.as_ref())
+    )
+}
+
+pub fn verify(
+    secret: &str,
+    token: &str,
+    kind: ResourceKind,
+    id: &str,
+    client: IpAddr,
+    now: u64,
+) -> Result<(), TokenError> {
+    let (payload, signature) = token.split_once('.').ok_or(TokenError::Malformed)?;
+    let payload = URL_SAFE_NO_PAD
+        .decode(payload)
+        .ok()
+        .and_then(|payload| String::from_utf8(payload).ok())
+        .ok_or(TokenError::Malformed)?;
+    let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| TokenError::Malformed)?;
+
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    hmac::verify(&key, message(kind, id, &payload).as_bytes(), &signature)
+        .map_err(|_| TokenError::BadSignature)?;
+
+    let (expires, ip) = payload.split_once('|').ok_or(TokenError::Malformed)?;
+    let expires: u64 = expires.parse().map_err(|_| TokenError::Malformed)?;
+    if now >= expires {
+        return Err(TokenError::Expired);
+    }
+    if !ip.is_empty() && ip.parse::<IpAddr>().ok() != Some(client) {
+        return Err(TokenError::WrongAddress);
+    }
+    Ok(())
+}
+
+fn message(kind: ResourceKind, id: &str, payload: &str) -> String {
+    format!("{}:{}|{}", kind.tag(), id, payload)
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// A signed token and when it expires.
+pub struct Grant {
+    pub token: String,
+    pub expires_at: u64,
+}
+
+impl PlaybackConfig {
+    /// Issue a token for `id` if signed URLs are enabled, bound to `client`
+    /// if given. `ttl` defaults to `token_ttl_secs`.
+    pub fn grant(
+        &self,
+        kind: ResourceKind,
+        id: &str,
+        ttl: Option<Duration>,
+        client: Option<IpAddr>,
+    ) -> Option<Grant> {
+        let secret = self.signing_secret.as_deref()?;
+        let ttl = ttl.unwrap_or(Duration::from_secs(self.token_ttl_secs));
+        let expires_at = unix_now() + ttl.as_secs();
+        Some(Grant {
+            token: sign(secret, kind, id, expires_at, client),
+            expires_at,
+        })
+    }
+
+    /// `url` with a token appended when signed URLs are enabled.
+    pub fn signed_url(&self, url: String, kind: ResourceKind, id: &str, client: IpAddr) -> String {
+        let client = self.bind_ip.then_some(client);
+        match self.grant(kind, id, None, client) {
+            Some(grant) => with_token(&url, &grant.token),
+            None => url,
+        }
+    }
+}
+
+fn with_token(uri: &str, token: &str) -> String {
+    let separator = if uri.contains('?') { '&' } else { '?' };
+    format!("{}{}token={}", uri, separator, token)
+}
+
+/// Append `token` to every URI in a playlist: the URI lines and any
+/// `URI="..."` attributes, so players carry it on to variants and segments.
+pub fn propagate_token(playlist: &str, token: &str) -> String {
+    let mut out = String::with_capacity(playlist.len());
+    for line in playlist.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            out.push_str(line);
+        } else if !trimmed.starts_with('#') {
+            out.push_str(&with_token(trimmed, token));
+        } else if let Some(start) = line.find("URI=\"") {
+            let value_start = start + "URI=\"".len();
+            match line[value_start..].find('"') {
+                Some(len) => {
+                    out.push_str(&line[..value_start]);
+                    out.push_str(&with_token(&line[value_start..value_start + len], token));
+                    out.push_str(&line[value_start + len..]);
+                }
+                None => out.push_str(line),
+            }
+        } else {
+            out.push_str(line);
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Middleware state for playback routes, whose `:id` names a resource of
+/// this kind.
+#[derive(Clone)]
+pub struct PlaybackAuthorizer {
+    config: SharedConfig,
+    kind: ResourceKind,
+}
+
+impl PlaybackAuthorizer {
+    pub fn new(config: SharedConfig, kind: ResourceKind) -> Self {
+        Self { config, kind }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct TokenQuery {
+    token: Option<String>,
+}
+
+/// Admit playback requests carrying a valid signed token for the resource in
+/// the path, and carry the token into any playlist served. Requests without
+/// a token need an API key with the `view` scope; with signed URLs enabled
+/// and API keys disabled, the token is required. A token grants its resource
+/// whatever the key limits, so it is attached as [`Access::All`].
+pub async fn authorize(
+    State(authorizer): State<PlaybackAuthorizer>,
+    Path(params): Path<HashMap<String, String>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    Query(query): Query<TokenQuery>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let config = authorizer.config.current();
+    let secret = config.playback.signing_secret.as_deref();
+    let token = match (secret, query.token) {
+        (Some(secret), Some(token)) => {
+            let id = params.get("id").map(String::as_str).unwrap_or_default();
+            if let Err(e) = verify(secret, &token, authorizer.kind, id, client.ip(), unix_now()) {
+                warn!("Rejected playback token for {}: {}", request.uri().path(), e);
+                return (StatusCode::FORBIDDEN, e.to_string()).into_response();
+            }
+            token
+        }
+        (Some(_), None) if !config.auth.enabled => {
+            return (StatusCode::UNAUTHORIZED, "Playback token required").into_response();
+        }
+        _ => {
+            let access = match auth::require_key(&config.auth, &request, Scope::View) {
+                Ok(access) => access,
+                Err(e) => return e.into_response(),
+            };
+            request.extensions_mut().insert(access);
+            return next.run(request).await;
+        }
+    };
+
+    request.extensions_mut().insert(Access::All);
+    let response = next.run(request).await;
+    let is_playlist = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|ctype| ctype == PLAYLIST_CONTENT_TYPE);
+    if !is_playlist || !response.status().is_success() {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let playlist = match axum::body::to_bytes(body, MAX_PLAYLIST_BYTES).await {
+        Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+        Err(e) => {
+            warn!("Failed to read playlist for token propagation: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(propagate_token(&playlist, &token)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "test-secret";
+
+    #[test]
+    fn tokens_are_bound_to_resource_expiry_and_address() {
+        let client = IpAddr::from([192, 168, 1, 20]);
+        let other = IpAddr::from([192, 168, 1, 21]);
+        let token = sign(SECRET, ResourceKind::Session, "abc", 1000, Some(client));
+
+        assert_eq!(verify(SECRET, &token, ResourceKind::Session, "abc", client, 999), Ok(()));
+        assert_eq!(
+            verify(SECRET, &token, ResourceKind::Session, "abc", client, 1000),
+            Err(TokenError::Expired)
+        );
+        assert_eq!(
+            verify(SECRET, &token, ResourceKind::Session, "abc", other, 999),
+            Err(TokenError::WrongAddress)
+        );
+        assert_eq!(
+            verify(SECRET, &token, ResourceKind::Session, "abd", client, 999),
+            Err(TokenError::BadSignature)
+        );
+        assert_eq!(
+            verify(SECRET, &token, ResourceKind::Stream, "abc", client, 999),
+            Err(TokenError::BadSignature)
+        );
+        assert_eq!(
+            verify("other-secret", &token, ResourceKind::Session, "abc", client, 999),
+            Err(TokenError::BadSignature)
+        );
+        assert_eq!(
+            verify(SECRET, "garbage", ResourceKind::Session, "abc", client, 999),
+            Err(TokenError::Malformed)
+        );
+
+        let unbound = sign(SECRET, ResourceKind::Stream, "lobby", 1000, None);
+        assert_eq!(verify(SECRET, &unbound, ResourceKind::Stream, "lobby", other, 999), Ok(()));
+    }
+
+    #[test]
+    fn propagates_token_into_playlist_uris() {
+        let playlist = "#EXTM3U\n\
+                        #EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"a\",URI=\"audio.m3u8\"\n\
+                        #EXT-X-STREAM-INF:BANDWIDTH=800000\n\
+                        /stream/hls/abc/stream_360p.m3u8\n\
+                        \n\
+                        #EXTINF:2.0,\n\
+                        /stream/hls/abc/segment001.ts?v=1\n";
+        assert_eq!(
+            propagate_token(playlist, "t.k"),
+            "#EXTM3U\n\
+             #EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"a\",URI=\"audio.m3u8?token=t.k\"\n\
+             #EXT-X-STREAM-INF:BANDWIDTH=800000\n\
+             /stream/hls/abc/stream_360p.m3u8?token=t.k\n\
+             \n\
+             #EXTINF:2.0,\n\
+             /stream/hls/abc/segment001.ts?v=1&token=t.k\n"
+        );
+    }
+}
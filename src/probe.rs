@@ -0,0 +1,110 @@
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+use tracing::warn;
+
+use crate::config::FfmpegConfig;
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Default)]
+pub struct VideoInfo {
+    pub codec: String,
+    pub profile: Option<String>,
+    pub level: Option<i64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub bit_rate: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SourceInfo {
+    pub video: Option<VideoInfo>,
+    pub has_audio: bool,
+}
+
+/// Inspect an RTSP source with ffprobe.
+///
+/// Only used where the output layout depends on what the source carries
+/// (e.g. rendition ladders need the source resolution and whether there is an
+/// audio track to map into every variant).
+pub async fn probe_source(ffmpeg: &FfmpegConfig, rtsp_url: &str) -> Result<SourceInfo> {
+    let ffprobe = &ffmpeg.ffprobe_path;
+    // Same protocol whitelist and transport as the ffmpeg that will read it
+    let mut args: Vec<String> = [
+        "-v", "error",
+        "-show_entries", "stream=codec_type,codec_name,profile,level,width,height,bit_rate",
+        "-of", "json",
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect();
+    args.extend(ffmpeg.input_args(rtsp_url));
+    let child = Command::new(ffprobe)
+        .args(&args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| anyhow!("Failed to start {}. Make sure FFmpeg is installed and in PATH: {}", ffprobe.display(), e))?;
+
+    let output = tokio::time::timeout(PROBE_TIMEOUT, child.wait_with_output())
+        .await
+        .map_err(|_| anyhow!("ffprobe timed out after {}s", PROBE_TIMEOUT.as_secs()))??;
+
+    if !output.status.success() {
+        return Err(anyhow!("ffprobe exited with {}", output.status));
+    }
+
+    let json: Value = serde_json::from_slice(&output.stdout)?;
+    Ok(parse_probe_output(&json))
+}
+
+/// Like [`probe_source`], but falls back to "video only, unknown format" when
+/// the probe fails so callers can still start a session.
+pub async fn probe_source_or_default(ffmpeg: &FfmpegConfig, rtsp_url: &str) -> SourceInfo {
+    match probe_source(ffmpeg, rtsp_url).await {
+        Ok(info) => info,
+        Err(e) => {
+            warn!("Source probe failed, assuming video-only source: {}", e);
+            SourceInfo::default()
+        }
+    }
+}
+
+fn parse_probe_output(json: &Value) -> SourceInfo {
+    let mut info = SourceInfo::default();
+    let streams = match json.get("streams").and_then(Value::as_array) {
+        Some(streams) => streams,
+        None => return info,
+    };
+
+    for stream in streams {
+        match stream.get("codec_type").and_then(Value::as_str) {
+            Some("video") if info.video.is_none() => {
+                info.video = Some(VideoInfo {
+                    codec: stream
+                        .get("codec_name")
+                        .and_then(Value::as_str)
+                        .unwrap_or_default()
+                        .to_string(),
+                    profile: stream.get("profile").and_then(Value::as_str).map(str::to_string),
+                    level: stream.get("level").and_then(Value::as_i64).filter(|l| *l > 0),
+                    width: stream.get("width").and_then(Value::as_u64).map(|w| w as u32),
+                    height: stream.get("height").and_then(Value::as_u64).map(|h| h as u32),
+                    // ffprobe reports bit_rate as a string
+                    bit_rate: stream
+                        .get("bit_rate")
+                        .and_then(Value::as_str)
+                        .and_then(|b| b.parse().ok()),
+                });
+            }
+            Some("audio") => info.has_audio = true,
+            _ => {}
+        }
+    }
+
+    info
+}
@@ -0,0 +1,227 @@
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+use crate::auth;
+use crate::config::{RateBudget, SharedConfig};
+use crate::metrics::Metrics;
+
+/// Buckets idle this long are refilled anyway and can be dropped.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Which `[rate_limits]` budget a route draws from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Budget {
+    Sessions,
+    Segments,
+    Nvr,
+}
+
+impl Budget {
+    fn name(self) -> &'static str {
+        match self {
+            Budget::Sessions => "sessions",
+            Budget::Segments => "segments",
+            Budget::Nvr => "nvr",
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets per client and budget, sized from the current
+/// `[rate_limits]` so reloads apply to the next request.
+#[derive(Clone)]
+pub struct RateLimiter {
+    config: SharedConfig,
+    metrics: Metrics,
+    buckets: Arc<Mutex<HashMap<Budget, Buckets>>>,
+}
+
+/// One budget's buckets, swept with that budget's rate and burst.
+struct Buckets {
+    by_client: HashMap<String, Bucket>,
+    swept: Instant,
+}
+
+impl Buckets {
+    fn new(now: Instant) -> Self {
+        Self {
+            by_client: HashMap::new(),
+            swept: now,
+        }
+    }
+}
+
+impl RateLimiter {
+    pub fn new(config: SharedConfig, metrics: Metrics) -> Self {
+        Self {
+            config,
+            metrics,
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Take a token for `client`, or say how long until one is available.
+    fn take(&self, budget: Budget, client: &str, now: Instant) -> Result<(), Duration> {
+        let config = self.config.current();
+        let limits = match budget {
+            Budget::Sessions => config.rate_limits.sessions,
+            Budget::Segments => config.rate_limits.segments,
+            Budget::Nvr => config.rate_limits.nvr,
+        };
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let buckets = buckets.entry(budget).or_insert_with(|| Buckets::new(now));
+        let result = take(buckets, limits, client, now);
+        if result.is_err() {
+            self.metrics.rate_limited(budget.name());
+        }
+        result
+    }
+}
+
+fn take(buckets: &mut Buckets, limits: RateBudget, client: &str, now: Instant) -> Result<(), Duration> {
+    if limits.per_minute == 0 {
+        return Ok(());
+    }
+    let rate = f64::from(limits.per_minute) / 60.0;
+    let burst = f64::from(limits.burst.unwrap_or(limits.per_minute));
+
+    if now.duration_since(buckets.swept) >= SWEEP_INTERVAL {
+        // A full bucket is the same as no bucket
+        buckets
+            .by_client
+            .retain(|_, bucket| bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < burst);
+        buckets.swept = now;
+    }
+
+    let bucket = buckets
+        .by_client
+        .entry(client.to_string())
+        .or_insert(Bucket { tokens: burst, updated: now });
+    let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+    bucket.updated = now;
+    if bucket.tokens >= 1.0 {
+        bucket.tokens -= 1.0;
+        Ok(())
+    } else {
+        Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+    }
+}
+
+/// Middleware state: the budget the routes it guards draw from.
+#[derive(Clone)]
+pub struct RateLimited {
+    limiter: RateLimiter,
+    budget: Budget,
+}
+
+impl RateLimited {
+    pub fn new(limiter: RateLimiter, budget: Budget) -> Self {
+        Self { limiter, budget }
+    }
+}
+
+/// Answer 429 once the caller's budget is spent. Callers are told apart by
+/// their API key, or by address when they don't present a known one;
+/// requests on the admin Unix socket have neither and aren't limited.
+pub async fn limit(
+    State(limited): State<RateLimited>,
+    client: Option<ConnectInfo<SocketAddr>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let config = limited.limiter.config.current();
+    let caller = match auth::key_name(&config.auth, request.headers()) {
+        Some(name) => format!("key:{}", name),
+        None => match client {
+            Some(ConnectInfo(addr)) => format!("ip:{}", addr.ip()),
+            None => return next.run(request).await,
+        },
+    };
+
+    match limited.limiter.take(limited.budget, &caller, Instant::now()) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => {
+            debug!(
+                "Rate limited {} on {} ({} budget)",
+                caller,
+                request.uri().path(),
+                limited.budget.name()
+            );
+            let retry_after = (wait.as_secs_f64().ceil() as u64).max(1).to_string();
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_after)],
+                "Too many requests; slow down",
+            )
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_refill_at_the_configured_rate() {
+        let start = Instant::now();
+        let mut buckets = Buckets::new(start);
+        let limits = RateBudget {
+            per_minute: 60,
+            burst: Some(2),
+        };
+        let mut take_at = |client: &str, secs: f64| {
+            take(&mut buckets, limits, client, start + Duration::from_secs_f64(secs))
+        };
+
+        assert_eq!(take_at("ip:10.0.0.1", 0.0), Ok(()));
+        assert_eq!(take_at("ip:10.0.0.1", 0.0), Ok(()));
+        let wait = take_at("ip:10.0.0.1", 0.0).unwrap_err();
+        assert!(wait <= Duration::from_secs(1), "{:?}", wait);
+        // Other clients have their own bucket
+        assert_eq!(take_at("ip:10.0.0.2", 0.0), Ok(()));
+        // One token a second comes back
+        assert_eq!(take_at("ip:10.0.0.1", 1.0), Ok(()));
+        assert!(take_at("ip:10.0.0.1", 1.5).is_err());
+
+        let unlimited = RateBudget::default();
+        for _ in 0..100 {
+            assert_eq!(take(&mut buckets, unlimited, "ip:10.0.0.1", start), Ok(()));
+        }
+    }
+
+    #[test]
+    fn budgets_are_swept_with_their_own_rates() {
+        let mut config = crate::config::Config::default();
+        config.rate_limits.sessions = RateBudget {
+            per_minute: 1,
+            burst: Some(1),
+        };
+        config.rate_limits.segments = RateBudget {
+            per_minute: 600,
+            burst: Some(1),
+        };
+        let limiter = RateLimiter::new(SharedConfig::new(Arc::new(config)), Metrics::new());
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+
+        assert_eq!(limiter.take(Budget::Sessions, "ip:10.0.0.1", at(30)), Ok(()));
+        // Sweeps the segments buckets; the spent sessions bucket must survive
+        assert_eq!(limiter.take(Budget::Segments, "ip:10.0.0.1", at(61)), Ok(()));
+        assert!(limiter.take(Budget::Sessions, "ip:10.0.0.1", at(62)).is_err());
+    }
+}
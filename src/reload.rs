@@ -0,0 +1,262 @@
+use anyhow::{anyhow, Result};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::{Mutex, RwLock};
+use tracing::{error, info, warn};
+
+use crate::capabilities::Capabilities;
+use crate::config::{Config, SharedConfig};
+use crate::logging::LogControl;
+use crate::stream_manager::{ReloadReport, StreamManager};
+
+/// Re-reads the configuration file and applies it without a restart.
+///
+/// Triggered by SIGHUP and `POST /api/admin/reload`. An invalid file is
+/// rejected as a whole and the running configuration stays in place.
+pub struct ConfigReloader {
+    path: Option<PathBuf>,
+    host_override: Option<String>,
+    port_override: Option<u16>,
+    config: SharedConfig,
+    stream_manager: Arc<RwLock<StreamManager>>,
+    logs: LogControl,
+    /// What the ffmpeg found at startup can encode; `None` if it couldn't be queried
+    capabilities: Option<Capabilities>,
+    /// Serializes reloads triggered concurrently by SIGHUP and the admin API
+    lock: Mutex<()>,
+}
+
+impl ConfigReloader {
+    pub fn new(
+        path: Option<PathBuf>,
+        host_override: Option<String>,
+        port_override: Option<u16>,
+        config: SharedConfig,
+        stream_manager: Arc<RwLock<StreamManager>>,
+        logs: LogControl,
+        capabilities: Option<Capabilities>,
+    ) -> Self {
+        Self {
+            path,
+            host_override,
+            port_override,
+            config,
+            stream_manager,
+            logs,
+            capabilities,
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub async fn reload(&self) -> Result<ReloadReport> {
+        let _guard = self.lock.lock().await;
+
+        let path = self
+            .path
+            .as_ref()
+            .ok_or_else(|| anyhow!("Server was started without --config; nothing to reload"))?;
+        let mut new = Config::load(path)?;
+        if let Some(host) = &self.host_override {
+            new.server.host = host.clone();
+        }
+        if let Some(port) = self.port_override {
+            new.server.port = port;
+        }
+
+        let current = self.config.current();
+        let mut warnings = keep_restart_only_settings(&mut new, &current);
+        // Left alone otherwise, so a filter set through the admin API stays
+        if new.logging != current.logging {
+            if let Err(e) = self.logs.apply_config(&new.logging) {
+                warnings.push(e.to_string());
+            }
+        }
+        if let Some(capabilities) = &self.capabilities {
+            warnings.extend(capabilities.apply(&mut new));
+        }
+
+        let new = Arc::new(new);
+        let mut report = self.stream_manager.write().await.apply_config(new.clone()).await;
+        self.config.replace(new);
+        report.warnings.extend(warnings);
+
+        info!(
+            "Configuration reloaded: {} added, {} removed, {} restarted, {} unchanged, {} failed",
+            report.added.len(),
+            report.removed.len(),
+            report.restarted.len(),
+            report.unchanged.len(),
+            report.failed.len()
+        );
+        for failure in &report.failed {
+            error!("Reload: failed to start stream {}", failure);
+        }
+        for warning in &report.warnings {
+            warn!("Reload: {}", warning);
+        }
+        Ok(report)
+    }
+
+    /// The log filter, which the admin API may change between reloads.
+    pub fn logs(&self) -> &LogControl {
+        &self.logs
+    }
+}
+
+/// Settings bound at startup keep their current values until a restart.
+/// Reverts each changed one in `new` and returns a warning for it.
+fn keep_restart_only_settings(new: &mut Config, current: &Config) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if new.server.host != current.server.host || new.server.port != current.server.port {
+        warnings.push("server.host/server.port changes require a restart".to_string());
+        new.server.host = current.server.host.clone();
+        new.server.port = current.server.port;
+    }
+    if new.server.registry_path != current.server.registry_path {
+        warnings.push("server.registry_path changes require a restart".to_string());
+        new.server.registry_path = current.server.registry_path.clone();
+    }
+    if new.cors != current.cors {
+        warnings.push("cors changes require a restart".to_string());
+        new.cors = current.cors.clone();
+    }
+    if new.admin != current.admin {
+        warnings.push("admin changes require a restart".to_string());
+        new.admin = current.admin.clone();
+    }
+    if new.tls != current.tls {
+        warnings.push("tls changes require a restart; renewed certificate files are picked up on their own".to_string());
+        new.tls = current.tls.clone();
+    }
+    if new.credentials != current.credentials {
+        warnings.push("credentials changes require a restart".to_string());
+        new.credentials = current.credentials.clone();
+    }
+    if new.logging.format != current.logging.format {
+        warnings.push("logging.format changes require a restart".to_string());
+        new.logging.format = current.logging.format;
+    }
+    if new.pid_dir() != current.pid_dir() {
+        warnings.push("server.pid_dir changes require a restart".to_string());
+        new.server.pid_dir = Some(current.pid_dir());
+    }
+    if new.ffmpeg.path != current.ffmpeg.path {
+        warnings.push("ffmpeg.path changes require a restart".to_string());
+        new.ffmpeg.path = current.ffmpeg.path.clone();
+    }
+    warnings
+}
+
+/// Reload the configuration whenever the process receives SIGHUP.
+#[cfg(unix)]
+pub fn spawn_sighup_handler(reloader: Arc<ConfigReloader>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    tokio::spawn(async move {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                error!("Failed to install SIGHUP handler: {}", e);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            info!("SIGHUP received; reloading configuration");
+            if let Err(e) = reloader.reload().await {
+                error!("Configuration reload failed: {}", e);
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn spawn_sighup_handler(_reloader: Arc<ConfigReloader>) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::admission::Admission;
+    use crate::credentials::CredentialStore;
+    use crate::janitor::ProcessTracker;
+    use crate::metrics::Metrics;
+
+    const CONFIG: &str = "[ffmpeg]\npath = \"true\"\n\n[[streams]]\nid = \"lobby\"\nurl = \"rtsp://10.0.0.1/live\"\n";
+
+    /// A reloader for a config file holding [`CONFIG`], with its stream running.
+    async fn reloader() -> (ConfigReloader, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("rtsp-proxy-reload-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        std::fs::write(&path, CONFIG).unwrap();
+        let config = Arc::new(Config::load(&path).unwrap());
+        let shared = SharedConfig::new(config.clone());
+        let mut manager = StreamManager::new(
+            config,
+            None,
+            ProcessTracker::new(dir.join("pids")).unwrap(),
+            Admission::new(shared.clone()),
+            CredentialStore::disabled(),
+            Metrics::new(),
+        );
+        manager.start_configured_streams().await;
+        let reloader = ConfigReloader::new(
+            Some(path),
+            None,
+            None,
+            shared,
+            Arc::new(RwLock::new(manager)),
+            LogControl::detached(),
+            None,
+        );
+        (reloader, dir)
+    }
+
+    #[tokio::test]
+    async fn restart_only_settings_are_kept_with_a_warning() {
+        let (reloader, dir) = reloader().await;
+        let before = reloader.config.current();
+        std::fs::write(
+            dir.join("config.toml"),
+            "[server]\nport = 8080\n\n[cors.control]\nallowed_origins = [\"https://admin.example.com\"]\n\n\
+             [hls]\nidle_timeout_secs = 5\n\n[ffmpeg]\npath = \"ffmpeg\"\n\n\
+             [[streams]]\nid = \"lobby\"\nurl = \"rtsp://10.0.0.2/live\"\n",
+        )
+        .unwrap();
+
+        let report = reloader.reload().await.unwrap();
+        assert_eq!(report.restarted, ["lobby"]);
+        assert_eq!(
+            report.warnings,
+            [
+                "server.host/server.port changes require a restart",
+                "cors changes require a restart",
+                "ffmpeg.path changes require a restart",
+            ]
+        );
+        let after = reloader.config.current();
+        assert_eq!(after.server.port, before.server.port);
+        assert_eq!(after.cors, before.cors);
+        assert_eq!(after.ffmpeg.path, PathBuf::from("true"));
+        assert_eq!(after.hls.idle_timeout_secs, 5);
+        reloader.stream_manager.write().await.stop_all().await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn an_invalid_file_leaves_the_running_config_alone() {
+        let (reloader, dir) = reloader().await;
+        let before = reloader.config.current();
+        for text in ["[server]\nport = 0\n", "[server\n", "[server]\nno_such_setting = 1\n"] {
+            std::fs::write(dir.join("config.toml"), text).unwrap();
+            assert!(reloader.reload().await.is_err(), "{:?} was accepted", text);
+            assert!(Arc::ptr_eq(&reloader.config.current(), &before));
+        }
+        let manager = reloader.stream_manager.read().await;
+        let lobby = manager.get_stream("lobby").unwrap();
+        assert!(lobby.active);
+        assert_eq!(lobby.rtsp_url, "rtsp://10.0.0.1/live");
+        drop(manager);
+        reloader.stream_manager.write().await.stop_all().await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,430 @@
+use anyhow::{anyhow, Result};
+
+use crate::capabilities::has_x264_options;
+use crate::config::{Config, Profile};
+use crate::probe::{SourceInfo, VideoInfo};
+
+/// Shorthand accepted in `renditions=` for the default adaptive ladder.
+pub const DEFAULT_LADDER: &str = "source,720p,360p";
+
+/// File name of the playlist handed to players. For single-rendition sessions
+/// ffmpeg writes it directly; for ladders it is the master playlist.
+pub const PLAYLIST_NAME: &str = "playlist.m3u8";
+
+/// Encoders every transcoded ladder variant uses, whatever the profile says.
+/// The video encoder may be swapped for a fallback; see `Capabilities::apply`.
+pub const LADDER_VIDEO_ENCODER: &str = "libx264";
+pub const LADDER_AUDIO_ENCODER: &str = "aac";
+
+/// Audio codec advertised for every variant (audio is always transcoded to AAC-LC).
+const AUDIO_CODEC: &str = "mp4a.40.2";
+
+/// Video codec advertised for transcoded variants: H.264 Constrained Baseline, level 4.0.
+/// Matches the `-profile`/`-level` forced on every transcoded variant.
+const TRANSCODE_VIDEO_CODEC: &str = "avc1.42c028";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rendition {
+    pub name: String,
+    /// Target height in pixels; `None` passes the source video through untouched.
+    pub height: Option<u32>,
+    pub video_bitrate_kbps: u32,
+    pub audio_bitrate_kbps: u32,
+}
+
+impl Rendition {
+    /// Built-in rendition presets. The bitrate of `source` is only an estimate
+    /// used for the master playlist when the camera doesn't advertise one.
+    pub fn preset(name: &str) -> Option<Self> {
+        let (height, video, audio) = match name {
+            "source" => (None, 4000, 128),
+            "1080p" => (Some(1080), 5000, 128),
+            "720p" => (Some(720), 2800, 128),
+            "480p" => (Some(480), 1400, 128),
+            "360p" => (Some(360), 800, 96),
+            "240p" => (Some(240), 400, 64),
+            _ => return None,
+        };
+        Some(Self {
+            name: name.to_string(),
+            height,
+            video_bitrate_kbps: video,
+            audio_bitrate_kbps: audio,
+        })
+    }
+
+    pub fn is_passthrough(&self) -> bool {
+        self.height.is_none()
+    }
+
+    pub fn playlist_name(&self) -> String {
+        format!("stream_{}.m3u8", self.name)
+    }
+}
+
+/// Parse a `renditions=` value such as `source,720p,360p` (or `abr` for the
+/// default ladder). An empty value yields an empty ladder, i.e. the classic
+/// single-rendition output.
+pub fn parse_ladder(spec: &str) -> Result<Vec<Rendition>> {
+    let spec = spec.trim();
+    let spec = if spec.eq_ignore_ascii_case("abr") { DEFAULT_LADDER } else { spec };
+
+    let mut ladder: Vec<Rendition> = Vec::new();
+    for name in spec.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+        let rendition = Rendition::preset(name).ok_or_else(|| {
+            anyhow!(
+                "Unknown rendition '{}'; expected one of source, 1080p, 720p, 480p, 360p, 240p",
+                name
+            )
+        })?;
+        if ladder.iter().any(|r| r.name == rendition.name) {
+            return Err(anyhow!("Rendition '{}' listed more than once", name));
+        }
+        ladder.push(rendition);
+    }
+    Ok(ladder)
+}
+
+/// Drop transcoded renditions that would upscale the source. At least one
+/// rendition (the smallest) is always kept.
+pub fn fit_ladder(ladder: Vec<Rendition>, source: &SourceInfo) -> Vec<Rendition> {
+    let source_height = match source.video.as_ref().and_then(|v| v.height) {
+        Some(h) => h,
+        None => return ladder,
+    };
+
+    let smallest = ladder
+        .iter()
+        .filter(|r| !r.is_passthrough())
+        .min_by_key(|r| r.height)
+        .cloned();
+    let fitted: Vec<Rendition> = ladder
+        .into_iter()
+        .filter(|r| r.height.is_none_or(|h| h <= source_height))
+        .collect();
+
+    if fitted.is_empty() {
+        smallest.into_iter().collect()
+    } else {
+        fitted
+    }
+}
+
+/// Path (relative to the session directory) that signals the session is
+/// playable: the only playlist for single renditions, the first variant for
+/// ladders (the master playlist is written up front).
+pub fn readiness_file(ladder: &[Rendition]) -> String {
+    match ladder.first() {
+        Some(r) => r.playlist_name(),
+        None => PLAYLIST_NAME.to_string(),
+    }
+}
+
+/// Build ffmpeg arguments writing HLS into `dir`. With an empty ladder this is
+/// a single rendition encoded with `profile`; otherwise all variants are
+/// produced by one ffmpeg process from a single upstream pull.
+pub fn hls_ffmpeg_args(
+    rtsp_url: &str,
+    dir: &str,
+    base_url: &str,
+    ladder: &[Rendition],
+    source: &SourceInfo,
+    config: &Config,
+    profile: &Profile,
+) -> Vec<String> {
+    let mut args = config.ffmpeg.input_args(rtsp_url);
+
+    if ladder.is_empty() {
+        args.extend(hls_muxer_args(base_url, config, &format!("{}/segment%03d.ts", dir)));
+        args.extend(profile.codec_args());
+        args.extend(profile.keyframe_args());
+        args.push(format!("{}/{}", dir, PLAYLIST_NAME));
+        return args;
+    }
+
+    let mut var_stream_map = Vec::new();
+    for (i, rendition) in ladder.iter().enumerate() {
+        args.extend(["-map".to_string(), "0:v:0".to_string()]);
+        if source.has_audio {
+            args.extend(["-map".to_string(), "0:a:0".to_string()]);
+            args.extend([format!("-b:a:{}", i), format!("{}k", rendition.audio_bitrate_kbps)]);
+            var_stream_map.push(format!("v:{},a:{},name:{}", i, i, rendition.name));
+        } else {
+            var_stream_map.push(format!("v:{},name:{}", i, rendition.name));
+        }
+
+        match rendition.height {
+            None => args.extend([format!("-c:v:{}", i), "copy".to_string()]),
+            Some(height) => {
+                let kbps = rendition.video_bitrate_kbps;
+                let encoder = config.encoders.ladder_encoder();
+                args.extend([
+                    format!("-filter:v:{}", i), format!("scale=-2:{}", height),
+                    format!("-c:v:{}", i), encoder.to_string(),
+                ]);
+                if has_x264_options(encoder) {
+                    args.extend([
+                        format!("-preset:v:{}", i), "ultrafast".to_string(),
+                        format!("-tune:v:{}", i), "zerolatency".to_string(),
+                        format!("-profile:v:{}", i), "baseline".to_string(),
+                        format!("-level:v:{}", i), "4.0".to_string(),
+                    ]);
+                }
+                args.extend([
+                    format!("-g:v:{}", i), profile.gop.to_string(),
+                    format!("-keyint_min:v:{}", i), (profile.gop / 2).max(1).to_string(),
+                    format!("-sc_threshold:v:{}", i), "0".to_string(),
+                    format!("-b:v:{}", i), format!("{}k", kbps),
+                    format!("-maxrate:v:{}", i), format!("{}k", kbps),
+                    format!("-bufsize:v:{}", i), format!("{}k", kbps * 2),
+                ]);
+            }
+        }
+    }
+
+    if source.has_audio {
+        args.extend(["-c:a", LADDER_AUDIO_ENCODER, "-ar", "44100"].iter().map(|s| s.to_string()));
+    }
+
+    args.extend(hls_muxer_args(base_url, config, &format!("{}/stream_%v_%03d.ts", dir)));
+    args.extend([
+        "-var_stream_map".to_string(),
+        var_stream_map.join(" "),
+        format!("{}/stream_%v.m3u8", dir),
+    ]);
+    args
+}
+
+fn hls_muxer_args(base_url: &str, config: &Config, segment_pattern: &str) -> Vec<String> {
+    vec![
+        "-f".to_string(),
+        "hls".to_string(),
+        "-hls_time".to_string(),
+        config.hls.segment_duration_secs.to_string(),
+        "-hls_list_size".to_string(),
+        config.hls.list_size.to_string(),
+        "-hls_flags".to_string(),
+        "delete_segments+independent_segments".to_string(),
+        "-hls_segment_filename".to_string(),
+        segment_pattern.to_string(),
+        "-hls_base_url".to_string(),
+        base_url.to_string(),
+    ]
+}
+
+/// Render the master playlist for a ladder. Written by us rather than ffmpeg
+/// because ffmpeg omits passthrough variants whose bitrate the camera doesn't
+/// advertise. Transcoded variants only advertise a codec when `encoder` is
+/// the one whose output is known.
+pub fn master_playlist(ladder: &[Rendition], source: &SourceInfo, encoder: &str) -> String {
+    let mut out = String::from("#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-INDEPENDENT-SEGMENTS\n");
+
+    for rendition in ladder {
+        let video = source.video.as_ref();
+        let (video_kbps, resolution, video_codec) = if rendition.is_passthrough() {
+            let kbps = video
+                .and_then(|v| v.bit_rate)
+                .map(|b| (b / 1000) as u32)
+                .filter(|k| *k > 0)
+                .unwrap_or(rendition.video_bitrate_kbps);
+            let resolution = video.and_then(|v| Some((v.width?, v.height?)));
+            (kbps, resolution, video.and_then(codec_string))
+        } else {
+            let height = rendition.height.unwrap_or_default();
+            let width = scaled_width(video, height);
+            (
+                rendition.video_bitrate_kbps,
+                Some((width, height)),
+                (encoder == LADDER_VIDEO_ENCODER).then(|| TRANSCODE_VIDEO_CODEC.to_string()),
+            )
+        };
+
+        let audio_kbps = if source.has_audio { rendition.audio_bitrate_kbps } else { 0 };
+        // 10% headroom for container overhead, as ffmpeg does
+        let bandwidth = u64::from(video_kbps + audio_kbps) * 1000 * 11 / 10;
+
+        let mut attrs = vec![format!("BANDWIDTH={}", bandwidth)];
+        if let Some((w, h)) = resolution {
+            attrs.push(format!("RESOLUTION={}x{}", w, h));
+        }
+        if let Some(codec) = video_codec {
+            let codecs = if source.has_audio {
+                format!("{},{}", codec, AUDIO_CODEC)
+            } else {
+                codec
+            };
+            attrs.push(format!("CODECS=\"{}\"", codecs));
+        }
+
+        out.push_str(&format!("#EXT-X-STREAM-INF:{}\n{}\n", attrs.join(","), rendition.playlist_name()));
+    }
+
+    out
+}
+
+/// Width matching `scale=-2:<height>`: keep the source aspect ratio (16:9 when
+/// unknown) and round to an even number.
+fn scaled_width(video: Option<&VideoInfo>, height: u32) -> u32 {
+    let (src_w, src_h) = match video.and_then(|v| Some((v.width?, v.height?))) {
+        Some((w, h)) if h > 0 => (w, h),
+        _ => (16, 9),
+    };
+    let width = (u64::from(src_w) * u64::from(height) + u64::from(src_h) / 2) / u64::from(src_h);
+    (width as u32 + 1) & !1
+}
+
+/// RFC 6381 codec string for a passthrough video stream, if we can tell.
+fn codec_string(video: &VideoInfo) -> Option<String> {
+    let level = video.level?;
+    match video.codec.as_str() {
+        "h264" => {
+            let (profile_idc, constraints) = match video.profile.as_deref()? {
+                "Constrained Baseline" => (0x42, 0x40),
+                "Baseline" => (0x42, 0x00),
+                "Main" => (0x4d, 0x00),
+                "Extended" => (0x58, 0x00),
+                "High" => (0x64, 0x00),
+                _ => return None,
+            };
+            Some(format!("avc1.{:02x}{:02x}{:02x}", profile_idc, constraints, level))
+        }
+        "hevc" => {
+            let profile = match video.profile.as_deref()? {
+                "Main" => "1.6",
+                "Main 10" => "2.4",
+                _ => return None,
+            };
+            Some(format!("hvc1.{}.L{}.B0", profile, level))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(ladder: &[Rendition]) -> Vec<&str> {
+        ladder.iter().map(|r| r.name.as_str()).collect()
+    }
+
+    fn h264(profile: &str, level: i64, width: u32, height: u32) -> VideoInfo {
+        VideoInfo {
+            codec: "h264".to_string(),
+            profile: Some(profile.to_string()),
+            level: Some(level),
+            width: Some(width),
+            height: Some(height),
+            bit_rate: None,
+        }
+    }
+
+    #[test]
+    fn parse_ladder_accepts_presets_and_abr() {
+        assert_eq!(names(&parse_ladder(" source, 720p ,360p").unwrap()), ["source", "720p", "360p"]);
+        assert_eq!(names(&parse_ladder("ABR").unwrap()), ["source", "720p", "360p"]);
+        assert!(parse_ladder("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn parse_ladder_rejects_unknown_and_repeated_renditions() {
+        assert!(parse_ladder("source,4k").unwrap_err().to_string().contains("Unknown rendition '4k'"));
+        assert!(parse_ladder("720p,720p").unwrap_err().to_string().contains("more than once"));
+    }
+
+    #[test]
+    fn fit_ladder_drops_upscaled_renditions() {
+        let source = SourceInfo {
+            video: Some(h264("Main", 40, 1280, 720)),
+            has_audio: true,
+        };
+        let ladder = fit_ladder(parse_ladder("source,1080p,720p,360p").unwrap(), &source);
+        assert_eq!(names(&ladder), ["source", "720p", "360p"]);
+    }
+
+    #[test]
+    fn fit_ladder_keeps_the_smallest_rendition_of_a_tiny_source() {
+        let source = SourceInfo {
+            video: Some(h264("Main", 30, 320, 180)),
+            has_audio: false,
+        };
+        assert_eq!(names(&fit_ladder(parse_ladder("720p,360p").unwrap(), &source)), ["360p"]);
+    }
+
+    #[test]
+    fn fit_ladder_keeps_everything_when_the_height_is_unknown() {
+        let ladder = fit_ladder(parse_ladder("1080p,240p").unwrap(), &SourceInfo::default());
+        assert_eq!(names(&ladder), ["1080p", "240p"]);
+    }
+
+    #[test]
+    fn codec_string_covers_h264_and_hevc_profiles() {
+        assert_eq!(codec_string(&h264("High", 40, 1920, 1080)).as_deref(), Some("avc1.640028"));
+        assert_eq!(codec_string(&h264("Constrained Baseline", 30, 640, 360)).as_deref(), Some("avc1.42401e"));
+        let hevc = VideoInfo {
+            codec: "hevc".to_string(),
+            level: Some(120),
+            ..h264("Main", 0, 1920, 1080)
+        };
+        assert_eq!(codec_string(&hevc).as_deref(), Some("hvc1.1.6.L120.B0"));
+    }
+
+    #[test]
+    fn codec_string_is_unknown_without_level_or_known_profile() {
+        assert_eq!(codec_string(&VideoInfo { level: None, ..h264("High", 40, 1920, 1080) }), None);
+        assert_eq!(codec_string(&h264("High 4:4:4", 40, 1920, 1080)), None);
+    }
+
+    #[test]
+    fn master_playlist_lists_variants_with_codecs() {
+        let source = SourceInfo {
+            video: Some(VideoInfo {
+                bit_rate: Some(3_000_000),
+                ..h264("Main", 40, 1920, 1080)
+            }),
+            has_audio: true,
+        };
+        let ladder = parse_ladder("source,360p").unwrap();
+        assert_eq!(
+            master_playlist(&ladder, &source, LADDER_VIDEO_ENCODER),
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-INDEPENDENT-SEGMENTS\n\
+             #EXT-X-STREAM-INF:BANDWIDTH=3440800,RESOLUTION=1920x1080,CODECS=\"avc1.4d0028,mp4a.40.2\"\n\
+             stream_source.m3u8\n\
+             #EXT-X-STREAM-INF:BANDWIDTH=985600,RESOLUTION=640x360,CODECS=\"avc1.42c028,mp4a.40.2\"\n\
+             stream_360p.m3u8\n"
+        );
+        // A fallback encoder's output isn't advertised
+        let playlist = master_playlist(&ladder, &source, "mpeg4");
+        assert!(playlist.contains("#EXT-X-STREAM-INF:BANDWIDTH=985600,RESOLUTION=640x360\n"), "{}", playlist);
+    }
+
+    #[test]
+    fn fallback_ladder_encoders_get_no_x264_options() {
+        let mut config = Config::default();
+        config.encoders.ladder = Ok("libopenh264".to_string());
+        let args = hls_ffmpeg_args(
+            "rtsp://cam/stream",
+            "/tmp/hls",
+            "/stream/hls/1/",
+            &parse_ladder("360p").unwrap(),
+            &SourceInfo::default(),
+            &config,
+            &Profile::default(),
+        );
+        assert!(args.windows(2).any(|pair| pair == ["-c:v:0", "libopenh264"]));
+        assert!(!args.iter().any(|arg| arg.starts_with("-preset") || arg.starts_with("-tune")));
+    }
+
+    #[test]
+    fn master_playlist_leaves_out_unknown_passthrough_codecs() {
+        let source = SourceInfo {
+            video: Some(VideoInfo {
+                codec: "mjpeg".to_string(),
+                ..h264("Main", 40, 640, 480)
+            }),
+            has_audio: false,
+        };
+        let playlist = master_playlist(&parse_ladder("source").unwrap(), &source, LADDER_VIDEO_ENCODER);
+        assert!(playlist.contains("#EXT-X-STREAM-INF:BANDWIDTH=4400000,RESOLUTION=640x480\n"), "{}", playlist);
+    }
+}
@@ -8,53 +8,76 @@ use tokio::sync::Mutex;
 use std::sync::Arc;
 use tracing::{error, info, warn};
 
+use crate::config::{FfmpegConfig, Profile};
+use crate::janitor::{ProcessTracker, TrackedPid};
+use crate::metrics::{FfmpegKind, Metrics};
+use crate::redact::Redacted;
+
 pub struct RtspClient {
     rtsp_url: String,
+    ffmpeg: FfmpegConfig,
+    profile: Profile,
+    processes: ProcessTracker,
+    metrics: Metrics,
     ffmpeg_process: Option<Child>,
+    ffmpeg_pid: Option<TrackedPid>,
     data_sender: Option<mpsc::UnboundedSender<Bytes>>,
     data_receiver: Arc<Mutex<Option<mpsc::UnboundedReceiver<Bytes>>>>,
 }
 
 impl RtspClient {
-    pub fn new(rtsp_url: String) -> Result<Self> {
+    pub fn new(
+        rtsp_url: String,
+        ffmpeg: FfmpegConfig,
+        profile: Profile,
+        processes: ProcessTracker,
+        metrics: Metrics,
+    ) -> Result<Self> {
         Ok(Self {
             rtsp_url,
+            ffmpeg,
+            profile,
+            processes,
+            metrics,
             ffmpeg_process: None,
+            ffmpeg_pid: None,
             data_sender: None,
             data_receiver: Arc::new(Mutex::new(None)),
         })
     }
 
     pub async fn start(&mut self) -> Result<()> {
-        info!("Starting RTSP client for {}", self.rtsp_url);
+        info!("Starting RTSP client for {}", Redacted(&self.rtsp_url));
 
         // Create channel for data
         let (tx, rx) = mpsc::unbounded_channel();
         self.data_sender = Some(tx.clone());
         *self.data_receiver.lock().await = Some(rx);
 
-        // Start FFmpeg process to convert RTSP to MPEG-TS
-        // FFmpeg command: ffmpeg -i rtsp://... -f mpegts -codec:v libx264 -preset ultrafast -tune zerolatency -b:v 2000k -codec:a aac pipe:1
-        let mut child = Command::new("ffmpeg")
-            .args(&[
-                "-rtsp_transport", "tcp",
-                "-i", &self.rtsp_url,
-                "-f", "mpegts",
-                "-codec:v", "libx264",
-                "-preset", "ultrafast",
-                "-tune", "zerolatency",
-                "-b:v", "2000k",
-                "-codec:a", "aac",
-                "-b:a", "128k",
-                "-avoid_negative_ts", "make_zero",
-                "-fflags", "+genpts",
-                "-",
-            ])
+        // Start FFmpeg process to convert RTSP to MPEG-TS using the stream's profile
+        let mut args = self.ffmpeg.input_args(&self.rtsp_url);
+        args.extend(["-f".to_string(), "mpegts".to_string()]);
+        args.extend(self.profile.codec_args());
+        args.extend(
+            ["-avoid_negative_ts", "make_zero", "-fflags", "+genpts", "-"]
+                .iter()
+                .map(|s| s.to_string()),
+        );
+
+        let mut child = Command::new(&self.ffmpeg.path)
+            .args(&args)
             .stdout(Stdio::piped())
             .stderr(Stdio::null())
             .kill_on_drop(true)
             .spawn()
-            .map_err(|e| anyhow!("Failed to start FFmpeg. Make sure FFmpeg is installed and in PATH: {}", e))?;
+            .map_err(|e| {
+                self.metrics.ffmpeg_failed(
+                    FfmpegKind::Managed,
+                    format!("{}: failed to start ffmpeg: {}", Redacted(&self.rtsp_url), e),
+                );
+                anyhow!("Failed to start FFmpeg. Make sure FFmpeg is installed and in PATH: {}", e)
+            })?;
+        self.metrics.ffmpeg_spawned(FfmpegKind::Managed);
 
         let stdout = child
             .stdout
@@ -88,6 +111,7 @@ impl RtspClient {
             }
         });
 
+        self.ffmpeg_pid = self.processes.track(&child);
         self.ffmpeg_process = Some(child);
 
         info!("RTSP client started successfully");
@@ -100,6 +124,7 @@ impl RtspClient {
         if let Some(mut process) = self.ffmpeg_process.take() {
             let _ = process.kill().await;
         }
+        self.ffmpeg_pid = None;
 
         self.data_sender = None;
 
@@ -113,6 +138,13 @@ impl RtspClient {
     pub fn is_active(&self) -> bool {
         self.ffmpeg_process.is_some()
     }
+
+    /// Whether ffmpeg was started and has since exited on its own.
+    pub fn has_exited(&mut self) -> bool {
+        self.ffmpeg_process
+            .as_mut()
+            .is_some_and(|process| !matches!(process.try_wait(), Ok(None)))
+    }
 }
 
 impl Drop for RtspClient {
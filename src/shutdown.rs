@@ -0,0 +1,157 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::watch;
+use tracing::error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    Running,
+    /// No new sessions are accepted; existing viewers keep playing
+    Draining,
+    /// Streams are being torn down; long-lived responses should end
+    Stopping,
+}
+
+/// Process-wide shutdown coordination shared by the server and its handlers.
+#[derive(Clone)]
+pub struct Shutdown {
+    phase: Arc<watch::Sender<Phase>>,
+    viewers: Arc<AtomicUsize>,
+}
+
+/// Counts a live streaming response (MPEG-TS or MJPEG) while it is held.
+pub struct ViewerGuard {
+    viewers: Arc<AtomicUsize>,
+}
+
+impl Drop for ViewerGuard {
+    fn drop(&mut self) {
+        self.viewers.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (phase, _) = watch::channel(Phase::Running);
+        Self {
+            phase: Arc::new(phase),
+            viewers: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn advance(&self, phase: Phase) {
+        self.phase.send_if_modified(|current| {
+            if phase > *current {
+                *current = phase;
+                true
+            } else {
+                false
+            }
+        });
+    }
+
+    pub fn is_draining(&self) -> bool {
+        *self.phase.borrow() >= Phase::Draining
+    }
+
+    /// Resolves once the server starts tearing streams down.
+    pub async fn stopping(&self) {
+        let mut rx = self.phase.subscribe();
+        let _ = rx.wait_for(|phase| *phase >= Phase::Stopping).await;
+    }
+
+    pub fn track_viewer(&self) -> ViewerGuard {
+        self.viewers.fetch_add(1, Ordering::Relaxed);
+        ViewerGuard {
+            viewers: self.viewers.clone(),
+        }
+    }
+
+    pub fn viewers(&self) -> usize {
+        self.viewers.load(Ordering::Relaxed)
+    }
+}
+
+/// Wait for Ctrl-C, or SIGTERM on Unix.
+pub async fn wait_for_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to install SIGTERM handler: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn phases_only_move_forward() {
+        let shutdown = Shutdown::new();
+        assert!(!shutdown.is_draining());
+
+        shutdown.advance(Phase::Draining);
+        assert!(shutdown.is_draining());
+        shutdown.advance(Phase::Running);
+        assert_eq!(*shutdown.phase.borrow(), Phase::Draining);
+
+        shutdown.advance(Phase::Stopping);
+        shutdown.advance(Phase::Draining);
+        assert_eq!(*shutdown.phase.borrow(), Phase::Stopping);
+        assert!(shutdown.is_draining());
+    }
+
+    #[tokio::test]
+    async fn stopping_resolves_once_streams_are_torn_down() {
+        let shutdown = Shutdown::new();
+        let waiting = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.stopping().await }
+        });
+
+        shutdown.advance(Phase::Draining);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiting.is_finished());
+
+        shutdown.advance(Phase::Stopping);
+        tokio::time::timeout(Duration::from_secs(1), waiting).await.unwrap().unwrap();
+        // Late callers don't wait for a change that already happened
+        tokio::time::timeout(Duration::from_secs(1), shutdown.stopping()).await.unwrap();
+    }
+
+    #[test]
+    fn viewer_guards_count_while_held() {
+        let shutdown = Shutdown::new();
+        let first = shutdown.track_viewer();
+        let second = shutdown.clone().track_viewer();
+        assert_eq!(shutdown.viewers(), 2);
+        drop(first);
+        assert_eq!(shutdown.viewers(), 1);
+        drop(second);
+        assert_eq!(shutdown.viewers(), 0);
+    }
+}
@@ -0,0 +1,146 @@
+use std::net::IpAddr;
+use thiserror::Error;
+use url::{Host, Url};
+
+use crate::config::SourcesConfig;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum SourceError {
+    #[error("Invalid source URL: {0}")]
+    Invalid(String),
+    #[error("Source URL scheme \"{scheme}\" is not allowed; expected one of {allowed}")]
+    Scheme { scheme: String, allowed: String },
+    #[error("Invalid source host \"{0}\"")]
+    Host(String),
+    #[error("Source host {host} is not allowed ({addr})")]
+    Denied { host: String, addr: IpAddr },
+    #[error("Could not resolve source host {0}")]
+    Unresolved(String),
+}
+
+/// A user-supplied source URL that passed the `[sources]` policy, safe to
+/// hand to ffmpeg.
+#[derive(Debug, Clone)]
+pub struct CheckedSource(String);
+
+impl CheckedSource {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn into_string(self) -> String {
+        self.0
+    }
+}
+
+impl SourcesConfig {
+    /// Check a source URL: its scheme must be allowed, and every address its
+    /// host resolves to must be outside `deny` and, if set, inside `allow`.
+    pub async fn check_url(&self, url: &str) -> Result<CheckedSource, SourceError> {
+        let parsed = Url::parse(url).map_err(|e| SourceError::Invalid(e.to_string()))?;
+        if !self.allowed_schemes.iter().any(|s| s.eq_ignore_ascii_case(parsed.scheme())) {
+            return Err(SourceError::Scheme {
+                scheme: parsed.scheme().to_string(),
+                allowed: self.allowed_schemes.join(", "),
+            });
+        }
+        let host = parsed
+            .host()
+            .ok_or_else(|| SourceError::Invalid("missing host".to_string()))?;
+        self.check_addresses(host.to_owned()).await?;
+        Ok(CheckedSource(url.to_string()))
+    }
+
+    /// Check a bare host, such as the `ip` of a Hikvision route, which is
+    /// put into URLs and so must not carry a path or login.
+    pub async fn check_host(&self, host: &str) -> Result<(), SourceError> {
+        let parsed = Host::parse(host).map_err(|_| SourceError::Host(host.to_string()))?;
+        self.check_addresses(parsed).await
+    }
+
+    async fn check_addresses(&self, host: Host<String>) -> Result<(), SourceError> {
+        if self.allow.is_empty() && self.deny.is_empty() {
+            return Ok(());
+        }
+        let addrs = match &host {
+            Host::Ipv4(addr) => vec![IpAddr::V4(*addr)],
+            Host::Ipv6(addr) => vec![IpAddr::V6(*addr)],
+            Host::Domain(domain) => tokio::net::lookup_host((domain.as_str(), 0))
+                .await
+                .map_err(|_| SourceError::Unresolved(domain.clone()))?
+                .map(|addr| addr.ip())
+                .collect(),
+        };
+        if addrs.is_empty() {
+            return Err(SourceError::Unresolved(host.to_string()));
+        }
+        match addrs.into_iter().find(|addr| !self.allows(*addr)) {
+            Some(addr) => Err(SourceError::Denied {
+                host: host.to_string(),
+                addr,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn allows(&self, addr: IpAddr) -> bool {
+        let addr = addr.to_canonical();
+        if self.deny.iter().any(|net| net.contains(&addr)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn checks_schemes_and_networks() {
+        let mut sources = SourcesConfig::default();
+        assert!(sources.check_url("rtsp://admin:pw@192.168.1.64:554/live").await.is_ok());
+        assert!(sources.check_url("RTSPS://192.168.1.64/live").await.is_ok());
+        assert!(matches!(
+            sources.check_url("file:///etc/passwd").await,
+            Err(SourceError::Scheme { .. })
+        ));
+        assert!(matches!(
+            sources.check_url("concat:a.ts|b.ts").await,
+            Err(SourceError::Scheme { .. })
+        ));
+        assert!(matches!(
+            sources.check_url("rtsp://169.254.169.254/latest").await,
+            Err(SourceError::Denied { .. })
+        ));
+        assert!(matches!(
+            sources.check_url("rtsp://[::ffff:127.0.0.1]/live").await,
+            Err(SourceError::Denied { .. })
+        ));
+
+        sources.allow = vec!["192.168.1.0/24".parse().unwrap()];
+        assert!(sources.check_url("rtsp://192.168.1.64/live").await.is_ok());
+        assert!(matches!(
+            sources.check_url("rtsp://10.0.0.1/live").await,
+            Err(SourceError::Denied { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn hosts_must_be_bare() {
+        let sources = SourcesConfig::default();
+        assert_eq!(sources.check_host("192.168.1.64").await, Ok(()));
+        assert!(matches!(
+            sources.check_host("evil.example/x?").await,
+            Err(SourceError::Host(_))
+        ));
+        assert!(matches!(
+            sources.check_host("user@192.168.1.64").await,
+            Err(SourceError::Host(_))
+        ));
+        assert!(matches!(
+            sources.check_host("127.0.0.1").await,
+            Err(SourceError::Denied { .. })
+        ));
+    }
+}
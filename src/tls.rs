@@ -0,0 +1,112 @@
+use anyhow::{anyhow, Context, Result};
+use axum::{
+    extract::Request,
+    http::{header, StatusCode, Uri},
+    response::{IntoResponse, Redirect, Response},
+    Router,
+};
+use axum_server::tls_rustls::RustlsConfig;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tracing::{error, info};
+
+use crate::config::TlsConfig;
+
+/// Load the configured certificate and key, with HTTP/2 and HTTP/1.1
+/// offered over ALPN.
+pub async fn load(config: &TlsConfig) -> Result<RustlsConfig> {
+    let (Some(cert), Some(key)) = (&config.cert_path, &config.key_path) else {
+        return Err(anyhow!("tls.cert_path and tls.key_path must be set"));
+    };
+    // Several crates link rustls; make ring the provider it uses
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    RustlsConfig::from_pem_file(cert, key).await.with_context(|| {
+        format!(
+            "Failed to load TLS certificate {} and key {}",
+            cert.display(),
+            key.display()
+        )
+    })
+}
+
+/// Watch the certificate and key files and load them again when either
+/// changes, so renewed certificates apply without a restart. A pair that
+/// fails to load is logged and the current one stays in use.
+pub fn spawn_reloader(rustls: RustlsConfig, config: &TlsConfig) {
+    let (Some(cert), Some(key)) = (config.cert_path.clone(), config.key_path.clone()) else {
+        return;
+    };
+    if config.reload_interval_secs == 0 {
+        return;
+    }
+    let interval = Duration::from_secs(config.reload_interval_secs);
+    tokio::spawn(async move {
+        let mut loaded = modified(&cert, &key).await;
+        loop {
+            tokio::time::sleep(interval).await;
+            let current = modified(&cert, &key).await;
+            if current == loaded {
+                continue;
+            }
+            match rustls.reload_from_pem_file(&cert, &key).await {
+                Ok(()) => {
+                    info!("Reloaded TLS certificate {}", cert.display());
+                    loaded = current;
+                }
+                // Retried on the next tick, e.g. once both files are written
+                Err(e) => error!("Failed to reload TLS certificate {}: {}", cert.display(), e),
+            }
+        }
+    });
+}
+
+async fn modified(cert: &Path, key: &Path) -> (Option<SystemTime>, Option<SystemTime>) {
+    let mtime = |path: PathBuf| async move { tokio::fs::metadata(path).await.and_then(|m| m.modified()).ok() };
+    (mtime(cert.to_path_buf()).await, mtime(key.to_path_buf()).await)
+}
+
+/// Router for the plain-HTTP listener: every request is redirected to the
+/// same path on the HTTPS port.
+pub fn redirect_router(https_port: u16) -> Router {
+    Router::new().fallback(move |request: Request| async move { redirect_to_https(&request, https_port) })
+}
+
+fn redirect_to_https(request: &Request, https_port: u16) -> Response {
+    let Some(host) = request.headers().get(header::HOST).and_then(|h| h.to_str().ok()) else {
+        return (StatusCode::BAD_REQUEST, "Missing Host header").into_response();
+    };
+    match https_url(host, request.uri(), https_port) {
+        Some(url) => Redirect::permanent(&url).into_response(),
+        None => (StatusCode::BAD_REQUEST, "Invalid Host header").into_response(),
+    }
+}
+
+/// `https://` URL for `uri` on `host`, with the port replaced by `https_port`.
+fn https_url(host: &str, uri: &Uri, https_port: u16) -> Option<String> {
+    let authority: axum::http::uri::Authority = host.parse().ok()?;
+    let host = authority.host();
+    let path = uri.path_and_query().map_or("/", |p| p.as_str());
+    Some(match https_port {
+        443 => format!("https://{}{}", host, path),
+        port => format!("https://{}:{}{}", host, port, path),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redirects_keep_host_and_path() {
+        let uri: Uri = "/stream/hls?rtsp_url=rtsp%3A%2F%2Fcam".parse().unwrap();
+        assert_eq!(
+            https_url("example.com:8080", &uri, 8443).as_deref(),
+            Some("https://example.com:8443/stream/hls?rtsp_url=rtsp%3A%2F%2Fcam")
+        );
+        assert_eq!(
+            https_url("[::1]:80", &"/".parse().unwrap(), 443).as_deref(),
+            Some("https://[::1]/")
+        );
+        assert_eq!(https_url("evil.com/x", &uri, 443), None);
+    }
+}